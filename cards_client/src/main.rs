@@ -1,19 +1,49 @@
 use smol::net::TcpStream;
-use cards_protocol::{Request, ClientProtocolStream};
+use cards_protocol::{Credentials, Message, Request, ClientProtocolStream, ClientTls};
 use cards_subscriber::{ApplyTo, Subscriber, TargetKind, Filter};
 use tracing::{Instrument, info};
 
+/// cards_client [ip:port] [--ca <file> | --pinned <file>] [--name <server name>]
+/// Without a CA or pinned certificates the connection is plaintext.
+/// The server name defaults to the host of the address.
 fn main() {
+    let mut address = "127.0.0.1:25566".to_string();
+    let mut tls = None;
+    let mut name = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ca" => {
+                let file = args.next().expect("--ca takes the file with the CA certificates");
+                tls = Some(ClientTls::with_roots(file).expect("Unable to load the CA certificates"));
+            }
+            "--pinned" => {
+                let file = args.next().expect("--pinned takes the file with the server certificates");
+                tls = Some(ClientTls::pinned(file).expect("Unable to load the pinned certificates"));
+            }
+            "--name" => name = Some(args.next().expect("--name takes the name on the server certificate")),
+            _ => address = arg,
+        }
+    }
+    let name = name.unwrap_or_else(|| {
+        let host = address.rsplit_once(':').map_or(address.as_str(), |(host, _)| host);
+        host.trim_start_matches('[').trim_end_matches(']').to_string()
+    });
     tracing::subscriber::set_global_default(Subscriber::new(
         "logs/client",
         &[],
         true,
     )
-    .filter(Filter::new(Some(tracing::Level::INFO), Some(TargetKind::Target("cards_protocol")), ApplyTo::Stdout))
+    .filter(Filter::new(Some(tracing::Level::INFO), Some(TargetKind::Target("cards_protocol".into())), ApplyTo::Stdout))
     ).unwrap();
     smol::block_on(async {
-        let stream = TcpStream::connect("127.0.0.1:25566").await.unwrap();
-        let mut client = ClientProtocolStream::new(stream);
+        let stream = TcpStream::connect(&address).await.unwrap();
+        let mut client = match &tls {
+            Some(tls) => ClientProtocolStream::new_tls(stream, tls, &name).await.unwrap(),
+            None => ClientProtocolStream::new(stream),
+        };
+        client.send(Message::Hello { credentials: Credentials::Anonymous }).await.unwrap();
+        info!("{:?}", client.recv().await);
         client.send(Request::Games).await.unwrap();
         info!("{:?}", client.recv().await);
     }.instrument(tracing::info_span!("client")));
@@ -0,0 +1,193 @@
+//! Frames of a bincode value after its length, as a little endian u32
+
+use serde::{Deserialize, Serialize};
+use smol::prelude::*;
+use tracing::trace;
+
+use std::{
+    convert::{TryFrom, TryInto},
+    io,
+    time::Instant,
+};
+
+/// The largest frame a reader takes unless told otherwise
+pub const DEFAULT_MAX_FRAME: usize = 1 << 24;
+const HEADER: usize = 4;
+/// The least room there is for a read
+const READ_SIZE: usize = 8 * 1024;
+/// The most the buffer grows at once, so a frame that claims to be big doesn't take the memory before it arrives
+const MAX_GROWTH: usize = 256 * 1024;
+
+/// The frame of a value, ready to be written
+pub fn encode<T: Serialize>(val: &T) -> io::Result<Vec<u8>> {
+    let size = bincode::serialized_size(val)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let size = u32::try_from(size).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The value is too big for a frame",
+        )
+    })?;
+    let mut frame = Vec::with_capacity(HEADER + size as usize);
+    frame.extend_from_slice(&size.to_le_bytes());
+    bincode::serialize_into(&mut frame, val)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(frame)
+}
+
+/// Reads frames through a buffer, decoding them in place.
+/// Reading can be cancelled at any point without losing anything, what arrived stays in the buffer.
+pub struct FrameReader {
+    buf: Vec<u8>,
+    /// The part of the buffer that was read and not decoded yet
+    start: usize,
+    end: usize,
+    max_frame: usize,
+    /// When the bytes of the frame at the front started arriving
+    started: Option<Instant>,
+}
+
+impl FrameReader {
+    /// Larger frames are an error, which leaves the reader unusable
+    pub fn new(max_frame: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            start: 0,
+            end: 0,
+            max_frame,
+            started: None,
+        }
+    }
+
+    /// When the frame being read started arriving, `None` if nothing of it has
+    pub fn started(&self) -> Option<Instant> {
+        self.started
+    }
+
+    /// The size of the frame at the front, once it's all there
+    fn complete(&self) -> io::Result<Option<usize>> {
+        let buffered = &self.buf[self.start..self.end];
+        if buffered.len() < HEADER {
+            return Ok(None);
+        }
+        let size =
+            u32::from_le_bytes([buffered[0], buffered[1], buffered[2], buffered[3]]) as usize;
+        if size > self.max_frame {
+            return Err(io::Error::other(format!(
+                "Frame of {} bytes, the limit is {}",
+                size, self.max_frame
+            )));
+        }
+        Ok(if buffered.len() - HEADER >= size {
+            Some(size)
+        } else {
+            None
+        })
+    }
+
+    /// Reads what is available, making room for the rest of the frame
+    async fn fill<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> io::Result<()> {
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+            // Gives back the memory of a big frame
+            if self.buf.len() > MAX_GROWTH {
+                self.buf = Vec::new();
+            }
+        }
+        // The space the frame at the front still needs, as far as it is known
+        let buffered = self.end - self.start;
+        let wanted = if buffered >= HEADER {
+            let size = u32::from_le_bytes(
+                self.buf[self.start..self.start + HEADER]
+                    .try_into()
+                    .unwrap(),
+            ) as usize;
+            (HEADER + size - buffered).clamp(READ_SIZE, MAX_GROWTH)
+        } else {
+            READ_SIZE
+        };
+        if self.buf.len() - self.end < wanted {
+            // Moves what is left to the front before growing
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+            if self.buf.len() - self.end < wanted {
+                self.buf.resize(self.end + wanted, 0);
+            }
+        }
+        let read = reader.read(&mut self.buf[self.end..]).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if self.start == self.end {
+            self.started = Some(Instant::now());
+        }
+        self.end += read;
+        Ok(())
+    }
+
+    /// The next value and the size of its frame. A value that doesn't decode is an `InvalidData` error,
+    /// its frame is skipped so the next one can be read.
+    pub async fn read<R, T>(&mut self, reader: &mut R) -> io::Result<(T, usize)>
+    where
+        R: AsyncRead + Unpin,
+        for<'de> T: Deserialize<'de>,
+    {
+        loop {
+            if let Some(size) = self.complete()? {
+                let body = self.start + HEADER;
+                let res = bincode::deserialize(&self.buf[body..body + size]);
+                self.start = body + size;
+                // Whatever is left is the start of the next frame
+                self.started = if self.start == self.end {
+                    None
+                } else {
+                    Some(Instant::now())
+                };
+                return match res {
+                    Ok(val) => Ok((val, size)),
+                    Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+                };
+            }
+            self.fill(reader).await?;
+        }
+    }
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME)
+    }
+}
+
+/// A stream with the buffer its frames are read through
+pub(crate) struct Framed<S> {
+    pub(crate) stream: S,
+    pub(crate) reader: FrameReader,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Framed<S> {
+    pub(crate) fn new(stream: S, max_frame: usize) -> Self {
+        Self {
+            stream,
+            reader: FrameReader::new(max_frame),
+        }
+    }
+
+    pub(crate) async fn recv<T>(&mut self) -> io::Result<(T, usize)>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        self.reader.read(&mut self.stream).await
+    }
+
+    /// Returns the size of the frame, without its length
+    pub(crate) async fn send<T: Serialize>(&mut self, val: &T) -> io::Result<usize> {
+        // In one write, so that small frames aren't held back waiting for an ack
+        let frame = encode(val)?;
+        trace!("Frame of {} bytes", frame.len() - HEADER);
+        self.stream.write_all(&frame).await?;
+        Ok(frame.len() - HEADER)
+    }
+}
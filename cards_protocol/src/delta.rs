@@ -0,0 +1,231 @@
+//! Differences between successive views of a game, so that only what changed is sent
+
+use crate::view::{Card, GameView, PileView, Prompt};
+
+use serde::{Deserialize, Serialize};
+
+/// Where a pile is in a view
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PileAt {
+    Common(usize),
+    /// The pile of a seat, by seat and then by pile
+    Seat(usize, usize),
+}
+
+/// A change to a view, every pile is changed by one of them at most so they can be applied in any order
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// Cards taken out of one pile and put in another, with how they look there if it isn't how they looked before
+    Moved {
+        from: PileAt,
+        from_index: usize,
+        to: PileAt,
+        to_index: usize,
+        count: usize,
+        cards: Option<Vec<Option<Card>>>,
+    },
+    /// Cards of a pile replaced by others. A face down pile that only grows or shrinks gets or loses hidden cards
+    Spliced {
+        pile: PileAt,
+        start: usize,
+        removed: usize,
+        cards: Vec<Option<Card>>,
+    },
+    Flipped {
+        pile: PileAt,
+        face_down: bool,
+    },
+    Turn(Option<usize>),
+    Prompt(Option<Prompt>),
+}
+
+/// The cards that changed in a pile, everything before and after them stayed
+struct Splice<'a> {
+    pile: PileAt,
+    start: usize,
+    removed: &'a [Option<Card>],
+    added: &'a [Option<Card>],
+}
+
+impl<'a> Splice<'a> {
+    fn new(pile: PileAt, old: &'a [Option<Card>], new: &'a [Option<Card>]) -> Option<Self> {
+        if old == new {
+            return None;
+        }
+        let start = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+        let end = old[start..]
+            .iter()
+            .rev()
+            .zip(new[start..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        Some(Self {
+            pile,
+            start,
+            removed: &old[start..old.len() - end],
+            added: &new[start..new.len() - end],
+        })
+    }
+}
+
+impl GameView {
+    /// Every pile, common ones first
+    fn pile_ids(&self) -> impl Iterator<Item = PileAt> + '_ {
+        let common = (0..self.piles.len()).map(PileAt::Common);
+        let seats = self
+            .player_piles
+            .iter()
+            .enumerate()
+            .flat_map(|(seat, piles)| (0..piles.len()).map(move |i| PileAt::Seat(seat, i)));
+        common.chain(seats)
+    }
+
+    pub fn pile(&self, at: PileAt) -> Option<&PileView> {
+        match at {
+            PileAt::Common(i) => self.piles.get(i),
+            PileAt::Seat(seat, i) => self.player_piles.get(seat)?.get(i),
+        }
+    }
+
+    fn pile_mut(&mut self, at: PileAt) -> Option<&mut PileView> {
+        match at {
+            PileAt::Common(i) => self.piles.get_mut(i),
+            PileAt::Seat(seat, i) => self.player_piles.get_mut(seat)?.get_mut(i),
+        }
+    }
+
+    /// The changes that turn this view into the newer one. `None` when they don't have the same piles,
+    /// or when the changes wouldn't be smaller than the newer view
+    pub fn diff(&self, new: &GameView) -> Option<Vec<Change>> {
+        let same_piles = self.seat == new.seat
+            && self.piles.len() == new.piles.len()
+            && self.player_piles.len() == new.player_piles.len()
+            && self
+                .player_piles
+                .iter()
+                .zip(&new.player_piles)
+                .all(|(a, b)| a.len() == b.len());
+        if !same_piles {
+            return None;
+        }
+        let mut changes = Vec::new();
+        let mut splices = Vec::new();
+        for at in self.pile_ids() {
+            let (old, new) = (self.pile(at)?, new.pile(at)?);
+            if old.face_down != new.face_down {
+                changes.push(Change::Flipped {
+                    pile: at,
+                    face_down: new.face_down,
+                });
+            }
+            splices.extend(Splice::new(at, &old.cards, &new.cards));
+        }
+        // Cards that left a pile are taken as moved to a pile that got as many, one where they look the same if there's one
+        let mut splices: Vec<Option<Splice>> = splices.into_iter().map(Some).collect();
+        for i in 0..splices.len() {
+            let (from, removed) = match &splices[i] {
+                Some(x) if x.added.is_empty() => (x.pile, x.removed),
+                _ => continue,
+            };
+            let arrivals = || {
+                splices.iter().enumerate().filter(|(_, x)| {
+                    x.as_ref().is_some_and(|x| {
+                        x.pile != from && x.removed.is_empty() && x.added.len() == removed.len()
+                    })
+                })
+            };
+            let target = arrivals()
+                .find(|(_, x)| x.as_ref().is_some_and(|x| x.added == removed))
+                .or_else(|| arrivals().next())
+                .map(|(j, _)| j);
+            if let Some(j) = target {
+                let (source, target) = (splices[i].take()?, splices[j].take()?);
+                changes.push(Change::Moved {
+                    from,
+                    from_index: source.start,
+                    to: target.pile,
+                    to_index: target.start,
+                    count: removed.len(),
+                    cards: if target.added == removed {
+                        None
+                    } else {
+                        Some(target.added.to_vec())
+                    },
+                });
+            }
+        }
+        changes.extend(splices.into_iter().flatten().map(|x| Change::Spliced {
+            pile: x.pile,
+            start: x.start,
+            removed: x.removed.len(),
+            cards: x.added.to_vec(),
+        }));
+        if self.turn != new.turn {
+            changes.push(Change::Turn(new.turn));
+        }
+        if self.prompt != new.prompt {
+            changes.push(Change::Prompt(new.prompt.clone()));
+        }
+        let smaller =
+            bincode::serialized_size(&changes).ok()? < bincode::serialized_size(new).ok()?;
+        if smaller {
+            Some(changes)
+        } else {
+            None
+        }
+    }
+
+    /// False if a change doesn't fit the view, which is then left half changed and has to be synced again
+    pub fn apply(&mut self, changes: &[Change]) -> bool {
+        changes
+            .iter()
+            .all(|change| self.apply_one(change).is_some())
+    }
+
+    fn apply_one(&mut self, change: &Change) -> Option<()> {
+        match change {
+            Change::Moved {
+                from,
+                from_index,
+                to,
+                to_index,
+                count,
+                cards,
+            } => {
+                let source = &mut self.pile_mut(*from)?.cards;
+                let end = from_index.checked_add(*count)?;
+                if end > source.len() {
+                    return None;
+                }
+                let moved: Vec<_> = source.drain(*from_index..end).collect();
+                let moved = match cards {
+                    Some(cards) if cards.len() == *count => cards.clone(),
+                    Some(_) => return None,
+                    None => moved,
+                };
+                let target = &mut self.pile_mut(*to)?.cards;
+                if *to_index > target.len() {
+                    return None;
+                }
+                target.splice(*to_index..*to_index, moved);
+            }
+            Change::Spliced {
+                pile,
+                start,
+                removed,
+                cards,
+            } => {
+                let pile = &mut self.pile_mut(*pile)?.cards;
+                let end = start.checked_add(*removed)?;
+                if end > pile.len() {
+                    return None;
+                }
+                pile.splice(*start..end, cards.iter().cloned());
+            }
+            Change::Flipped { pile, face_down } => self.pile_mut(*pile)?.face_down = *face_down,
+            Change::Turn(turn) => self.turn = *turn,
+            Change::Prompt(prompt) => self.prompt = prompt.clone(),
+        }
+        Some(())
+    }
+}
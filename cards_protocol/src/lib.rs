@@ -1,118 +1,995 @@
 use serde::{Deserialize, Serialize};
 
+use smol::channel::{Receiver, Sender};
+use smol::io::{ReadHalf, WriteHalf};
 use smol::lock::RwLock;
 use smol::net::TcpStream;
 use smol::prelude::*;
+use smol::Task;
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
 
 pub use uuid::Uuid;
 
-use tracing::{trace, instrument};
+use tracing::{instrument, trace, warn};
+
+pub mod codec;
+use codec::{FrameReader, Framed, DEFAULT_MAX_FRAME};
+mod queue;
+use queue::{Kind, Outbox, Pushed};
+mod tls;
+use tls::Stream;
+pub use tls::{ClientTls, ServerTls};
+mod view;
+pub use view::{Card, GameView, PileId, PileView, Prompt};
+mod delta;
+pub use delta::{Change, PileAt};
+
+/// What a client can send, the handshake and chat are apart from the other requests since they depend on the connection
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    /// The first message of a connection, servers that require authentication reject every request before it succeeds
+    Hello { credentials: Credentials },
+    /// Rate limited per connection
+    Chat { room: Uuid, text: String },
+    Request(Request),
+}
+
+impl From<Request> for Message {
+    fn from(req: Request) -> Self {
+        Message::Request(req)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
     Games,
+    Rooms,
+    CreateRoom { game: String, settings: RoomSettings },
+    /// The password is only needed for protected rooms
+    JoinRoom { room: Uuid, password: Option<String> },
+    Spectate { room: Uuid, omniscient: bool, password: Option<String> },
+    /// Joins the room with that invite code as a player, no password needed
+    JoinInvite { code: String },
+    LeaveRoom { room: Uuid },
+    StartGame { room: Uuid },
+    Click { room: Uuid, pile: PileId },
+    /// The piles the player may click right now
+    LegalActions { room: Uuid },
+    /// Sends the whole state again, for when a delta was missed
+    Resync { room: Uuid },
+    /// Answers the pending prompt with the index of one of its options
+    Answer { room: Uuid, option: usize },
+    /// Only for the host, seats a bot before the game starts
+    AddBot { room: Uuid },
+    Members { room: Uuid },
+    /// Only for the host, muted members can't chat
+    Mute { room: Uuid, member: Uuid, muted: bool },
+    /// Only for the host
+    Kick { room: Uuid, member: Uuid },
+    /// Only for the host, banned connections and names can't come back, banning kicks the member
+    Ban { room: Uuid, member: Uuid, banned: bool },
+    /// Asks the other players to take back the last action, or every action of the last turn
+    ProposeUndo { room: Uuid, whole_turn: bool },
+    VoteUndo { room: Uuid, accept: bool },
+    /// The name the player's results are kept under, unnamed players aren't rated.
+    /// Connections authenticated with an account keep its name
+    SetName { name: String },
+    /// The records of a player in every version of a game
+    Stats { player: String, game: String },
+    /// The best rated players of the loaded version of a game
+    Leaderboard { game: String },
+    /// Waits for a room of the given size to be formed automatically,
+    /// balanced queues group players of similar rating
+    Enqueue { game: String, players: usize, balanced: bool },
+    Dequeue,
+    /// The creator administers the tournament, both need a name
+    CreateTournament { game: String, format: TournamentFormat },
+    JoinTournament { tournament: Uuid },
+    /// Only for the admin of the tournament or of the server, also recreates the rooms of a tournament resumed after a restart
+    StartTournament { tournament: Uuid },
+    Tournament { tournament: Uuid },
+    /// The options rooms of a game can choose
+    GameOptions { game: String },
+    /// Only for server admins
+    Admin(AdminRequest),
+}
+
+/// Operations on the running server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum AdminRequest {
+    Connections,
+    /// Every room, unlisted ones included
+    Rooms,
+    /// Everything about a room, hidden cards included
+    InspectRoom { room: Uuid },
+    /// Removes a member from a room as if the host kicked it
+    Kick { room: Uuid, member: Uuid },
+    /// Closes a connection
+    Disconnect { conn: Uuid },
+    /// Kicks everyone out of the room and removes it
+    CloseRoom { room: Uuid },
+    /// Loads the games folder again, rooms keep playing the version they were created with
+    ReloadGames,
+    /// Shows the messages of a target up to the level, on every output
+    SetLogFilter { target: String, level: LogLevel },
 }
+/// How a connection proves it may use the server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Credentials {
+    Anonymous,
+    /// The password shared by everyone allowed on the server
+    ServerPassword(String),
+    Account { name: String, password: String },
+    /// A token the server handed out on an earlier login
+    Token(String),
+}
+
+/// What a client sends
 #[derive(Serialize, Deserialize, Debug, Clone)]
 enum ServerRequest {
     Close,
-    Message(Request)
+    Message(Message),
+    /// The answer to a ping, with its number
+    Pong(u64),
+}
+
+/// What the server sends, the reply is borrowed so it isn't copied to be sent
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum ClientMessage<'a> {
+    Message(Cow<'a, Reply>),
+    /// Asks the client to answer with a pong of the same number, to know it is still there
+    Ping(u64),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Reply {
+    /// Accounts get their name and a token to log in with until it expires
+    Welcome { name: Option<String>, admin: bool, token: Option<String> },
     Games(Vec<(String, String)>),
+    Rooms(Vec<RoomInfo>),
+    /// Rooms that aren't public get an invite code.
+    /// It lets players into password rooms without the password, so it's as secret as the password
+    RoomCreated { room: Uuid, invite: Option<String> },
+    /// The seat is `None` when joining as a spectator
+    Joined { room: Uuid, seat: Option<usize> },
+    Members { host: Uuid, players: Vec<Seat>, spectators: Vec<Uuid> },
+    LegalActions { room: Uuid, actions: Vec<PileId> },
+    /// Records by game version
+    Stats { player: String, game: String, records: Vec<(String, PlayerRecord)> },
+    Leaderboard { game: String, version: String, players: Vec<(String, PlayerRecord)> },
+    TournamentCreated(Uuid),
+    GameOptions { game: String, options: Vec<GameOption> },
+    Tournament(TournamentInfo),
+    Admin(AdminReply),
+    Ok,
+    Rejected(Rejection),
+    Event(Event),
+}
+
+/// Messages the server sends without them being a reply to a request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Event {
+    /// The whole view, the deltas that follow build on it
+    State { room: Uuid, seq: u64, view: GameView },
+    /// How the view changed since the state or delta numbered one less. After a gap the client should ask for a resync
+    StateDelta { room: Uuid, seq: u64, changes: Vec<Change> },
+    ChatMessage { room: Uuid, from: Uuid, text: String },
+    Kicked { room: Uuid },
+    TimerStarted { room: Uuid, seat: usize, kind: TimerKind, deadline: SystemTime },
+    UndoProposed { room: Uuid, seat: usize, whole_turn: bool },
+    /// The undo was applied if every other player accepted it, it is dropped if the game moves on first
+    UndoResolved { room: Uuid, accepted: bool },
+    /// Standings are seats from first to last, scores are indexed by seat
+    GameOver { room: Uuid, standings: Vec<usize>, scores: Vec<i64> },
+    /// The position starts at 1, the estimated wait in seconds is unknown until the queue has formed a couple of rooms
+    QueuePosition { game: String, position: usize, eta: Option<u64> },
+    /// The queue put the player in a started room
+    Matched { room: Uuid, seat: usize },
+    /// Players with their points, from first to last
+    TournamentOver { tournament: Uuid, standings: Vec<(String, u32)> },
+    /// Games in progress can go on until the deadline, then every connection is closed
+    ServerShuttingDown { deadline: SystemTime },
+    /// The last event of a connection the server closes
+    Disconnected { reason: DisconnectReason },
+}
+
+/// Why the server closed a connection
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The events for it piled up faster than it read them
+    TooSlow,
+    /// By an admin
+    Admin,
+    ShuttingDown,
+    /// The handshake failed too many times
+    AuthenticationFailed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerKind {
+    Turn,
+    Prompt,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Rejection {
+    NoSuchGame,
+    NoSuchRoom,
+    NotInRoom,
+    NotHost,
+    RoomFull,
+    NotEnoughPlayers,
+    AlreadyStarted,
+    NotStarted,
+    SpectatorsDisabled,
+    NoBot,
+    OmniscientDisabled,
+    Spectating,
+    /// Players can't spectate the room they are seated in
+    AlreadySeated,
+    IllegalAction,
+    MessageTooLong,
+    EmptyMessage,
+    RateLimited,
+    Muted,
+    /// The history is empty, or was cleared by an action that can't be taken back
+    NothingToUndo,
+    UndoPending,
+    NoUndoPending,
+    /// The game is over, the host may start a rematch
+    Finished,
+    InvalidName,
+    NameTaken,
+    /// Outside of the game's range of players
+    InvalidPlayerCount,
+    NotQueued,
+    /// Set a name first
+    Unnamed,
+    NoSuchTournament,
+    NotAdmin,
+    /// The option is unknown or the value doesn't fit it
+    InvalidOption(String),
+    Banned,
+    WrongPassword,
+    /// Send a successful hello first
+    Unauthenticated,
+    /// The credentials were wrong, expired or not accepted by the server
+    AuthenticationFailed,
+    /// The connection already authenticated
+    AlreadyAuthenticated,
+    /// The name comes from the account the connection logged in with
+    NameLocked,
+    /// Log in with an account first, names that anyone can take don't count
+    NotLoggedIn,
+    /// The server has as many rooms open as it allows
+    TooManyRooms,
+    /// The server has that feature turned off
+    Disabled,
+    /// Nothing new starts while the server shuts down
+    ShuttingDown,
+    Game(String),
+    /// A handler of the game failed with this message, the game was left as it was before
+    ActionFailed(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Seat {
+    /// Left by a player after the game started
+    Empty,
+    Player(Uuid),
+    Bot,
+}
+
+impl Seat {
+    pub fn player(&self) -> Option<&Uuid> {
+        match self {
+            Seat::Player(uuid) => Some(uuid),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RoomSettings {
+    /// If set, spectators may ask for an omniscient view, which is sent this many seconds late
+    pub omniscient_delay: Option<u64>,
+    /// Overrides the game's seconds per turn, 0 disables the timer
+    pub turn_time: Option<u64>,
+    /// Overrides the game's seconds per prompt, 0 disables the timer
+    pub prompt_time: Option<u64>,
+    /// Whether a bot takes the seat of a player that leaves a started game
+    pub bots_take_over: bool,
+    /// Values for the options of the game, the ones left out get their default
+    pub options: BTreeMap<String, OptionValue>,
+    pub visibility: Visibility,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub enum Visibility {
+    #[default]
+    Public,
+    /// Left out of the listings, joined with the invite code
+    Unlisted,
+    /// Listed as locked, joined with the password or the invite code
+    Password(String),
+}
+
+/// A choice a game leaves to each room, like a house rule
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameOption {
+    pub name: String,
+    pub description: Option<String>,
+    pub kind: OptionKind,
+    pub default: OptionValue,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum OptionKind {
+    Bool,
+    /// One of the values
+    Enum(Vec<String>),
+    /// Inclusive
+    Range { min: i64, max: i64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum OptionValue {
+    Bool(bool),
+    Text(String),
+    Number(i64),
+}
+
+impl GameOption {
+    pub fn accepts(&self, value: &OptionValue) -> bool {
+        match (&self.kind, value) {
+            (OptionKind::Bool, OptionValue::Bool(_)) => true,
+            (OptionKind::Enum(values), OptionValue::Text(x)) => values.contains(x),
+            (OptionKind::Range { min, max }, OptionValue::Number(x)) => min <= x && x <= max,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlayerRecord {
+    pub played: u32,
+    pub wins: u32,
+    pub losses: u32,
+    pub rating: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TournamentFormat {
+    /// Players with similar points meet each round, without rematches if possible
+    Swiss { rounds: usize },
+    /// Winners go through to the next round until only one is left
+    SingleElimination,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TournamentInfo {
+    pub id: Uuid,
+    pub game: String,
+    pub format: TournamentFormat,
+    pub admin: String,
+    pub players: Vec<String>,
+    pub rounds: Vec<Vec<Pairing>>,
+    /// Players with their points, from first to last
+    pub standings: Vec<(String, u32)>,
+    pub started: bool,
+    pub finished: bool,
 }
 
+/// A match of a tournament round, a pairing with a single player is a bye
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pairing {
+    pub players: Vec<String>,
+    pub room: Option<Uuid>,
+    pub winner: Option<String>,
+    pub done: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum AdminReply {
+    Connections(Vec<ConnectionInfo>),
+    Rooms(Vec<RoomInfo>),
+    Room(Box<RoomDetails>),
+    /// The games now loaded
+    GamesReloaded(Vec<(String, String)>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: Uuid,
+    pub address: Option<std::net::SocketAddr>,
+    pub name: Option<String>,
+    pub admin: bool,
+    /// The rooms it is a member of
+    pub rooms: Vec<Uuid>,
+    /// How long it took to answer the last ping
+    pub latency: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomDetails {
+    pub info: RoomInfo,
+    pub host: Uuid,
+    pub players: Vec<Seat>,
+    pub spectators: Vec<Uuid>,
+    /// Counts the changes to the game
+    pub version: u64,
+    /// The omniscient view, once the game started
+    pub view: Option<GameView>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomInfo {
+    pub id: Uuid,
+    pub game: String,
+    pub players: usize,
+    pub bots: usize,
+    pub spectators: usize,
+    pub started: bool,
+    pub finished: bool,
+    /// Needs a password
+    pub locked: bool,
+    /// The value of every option of the game
+    pub options: BTreeMap<String, OptionValue>,
+}
+
+/// How long to wait on the peer
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// How often the server pings a connection
+    pub ping_interval: Duration,
+    /// Connections that send nothing for this long, not even a pong, are dead
+    pub idle: Duration,
+    /// Once a frame starts, the rest of it has to arrive within this
+    pub frame: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(15),
+            idle: Duration::from_secs(45),
+            frame: Duration::from_secs(5),
+        }
+    }
+}
+
+/// When a peer was last heard from, and how long it takes to answer a ping
+struct Liveness {
+    last_seen: Instant,
+    last_ping: Instant,
+    /// The ping waiting for its pong, with when it was sent
+    pending: Option<(u64, Instant)>,
+    next_ping: u64,
+    latency: Option<Duration>,
+}
+
+impl Liveness {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            last_seen: now,
+            last_ping: now,
+            pending: None,
+            next_ping: 0,
+            latency: None,
+        }
+    }
+
+    /// The number of a new ping
+    fn ping(&mut self) -> u64 {
+        let now = Instant::now();
+        let ping = self.next_ping;
+        self.next_ping += 1;
+        self.last_ping = now;
+        self.pending = Some((ping, now));
+        ping
+    }
+
+    fn pong(&mut self, ping: u64) {
+        if let Some((pending, sent)) = self.pending {
+            if pending == ping {
+                self.latency = Some(sent.elapsed());
+                self.pending = None;
+            }
+        }
+    }
+}
+
+/// Requests read ahead while the last one is handled, after that the reader waits
+const READ_AHEAD: usize = 8;
+/// How long what is queued for a closed connection has to be sent
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// The bytes queued for a connection before it's considered slow
+pub const DEFAULT_MAX_QUEUE: usize = 1 << 20;
+/// How long a slow connection has to catch up
+pub const DEFAULT_QUEUE_GRACE: Duration = Duration::from_secs(5);
+
+/// An open connection, read and written by tasks of its own
+struct Connection {
+    /// Frames for the writer
+    outbox: Arc<Outbox>,
+    /// What the reader got
+    incoming: Receiver<io::Result<Message>>,
+    /// Ends once everything queued is sent
+    writer: Task<()>,
+    /// Kept apart to shut it down
+    tcp: TcpStream,
+    liveness: Arc<Mutex<Liveness>>,
+}
+
+/// Gets told about the traffic of a server, to keep metrics
+pub trait Observer: Send + Sync {
+    /// The size of a frame without its length
+    fn frame_received(&self, _bytes: usize) {}
+    /// Once it is written, a frame that gets replaced or dropped while queued isn't sent
+    fn frame_sent(&self, _bytes: usize) {}
+    /// A frame that isn't a valid request
+    fn decode_failed(&self) {}
+    /// A state event that was still queued got replaced by a newer one
+    fn state_replaced(&self) {}
+    /// A connection was dropped for not reading what was sent to it
+    fn too_slow(&self) {}
+}
+
+/// Every connection has a task reading it and another writing it,
+/// so anyone can send to a connection while its requests are being received
 pub struct ServerProtocol {
-    streams: Arc<RwLock<HashMap<uuid::Uuid, Arc<RwLock<TcpStream>>>>>,
+    streams: Arc<RwLock<HashMap<uuid::Uuid, Connection>>>,
+    tls: Option<ServerTls>,
+    observer: Option<Arc<dyn Observer>>,
+    timeouts: Timeouts,
+    max_frame: usize,
+    max_queue: usize,
+    queue_grace: Duration,
 }
 
 impl ServerProtocol {
     pub fn new() -> Self {
         Self {
             streams: Default::default(),
+            tls: None,
+            observer: None,
+            timeouts: Timeouts::default(),
+            max_frame: DEFAULT_MAX_FRAME,
+            max_queue: DEFAULT_MAX_QUEUE,
+            queue_grace: DEFAULT_QUEUE_GRACE,
+        }
+    }
+
+    /// Every connection goes through the TLS handshake first
+    pub fn with_tls(tls: ServerTls) -> Self {
+        Self {
+            tls: Some(tls),
+            ..Self::new()
         }
     }
 
-    async fn get(&self, uuid: &Uuid) -> Option<Arc<RwLock<TcpStream>>> {
-        self.streams.read().await.get(uuid).map(|x| x.clone())
+    pub fn observed_by(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.observer = Some(observer);
+        self
     }
 
-    async fn remove(&self, uuid: &Uuid) -> bool {
-        self.streams.write().await.remove(uuid).is_some()
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
     }
 
-    pub async fn connection(&self, tcp: TcpStream) -> Uuid {
+    /// Connections that send a larger request are closed
+    pub fn with_max_frame(mut self, max_frame: usize) -> Self {
+        self.max_frame = max_frame;
+        self
+    }
+
+    /// Connections with more than that many bytes queued for longer than the grace, or twice as many, are disconnected
+    pub fn with_queue_limit(mut self, max_queue: usize, grace: Duration) -> Self {
+        self.max_queue = max_queue;
+        self.queue_grace = grace;
+        self
+    }
+
+    pub async fn connections(&self) -> Vec<Uuid> {
+        self.streams.read().await.keys().copied().collect()
+    }
+
+    /// Forgets the connection, what is queued for it is still sent, followed by the reason if there's one
+    async fn remove(&self, uuid: &Uuid, reason: Option<DisconnectReason>) -> Option<Connection> {
+        let connection = self.streams.write().await.remove(uuid)?;
+        let last = reason.map(|reason| {
+            let event = Reply::Event(Event::Disconnected { reason });
+            codec::encode(&ClientMessage::Message(Cow::Owned(event)))
+        });
+        match last {
+            Some(Ok(frame)) => connection.outbox.last(frame),
+            _ => connection.outbox.close(),
+        }
+        Some(connection)
+    }
+
+    /// Forgets the connection and ends it once what is queued for it is sent
+    pub async fn close(&self, uuid: &Uuid) {
+        if let Some(connection) = self.remove(uuid, None).await {
+            finish(connection).await;
+        }
+    }
+
+    /// Closes the connection telling it why, once what is queued for it is sent
+    pub async fn disconnect(&self, uuid: &Uuid, reason: DisconnectReason) {
+        if let Some(connection) = self.remove(uuid, Some(reason)).await {
+            finish(connection).await;
+        }
+    }
+
+    pub async fn disconnect_all(&self, reason: DisconnectReason) {
+        for uuid in self.connections().await {
+            self.disconnect(&uuid, reason).await;
+        }
+    }
+
+    /// Queues a frame, disconnecting the connection if it can't keep up
+    async fn push(
+        &self,
+        uuid: &Uuid,
+        outbox: &Outbox,
+        frame: Vec<u8>,
+        kind: Kind,
+    ) -> io::Result<Pushed> {
+        match outbox.push(frame, kind) {
+            Pushed::Closed => Err(io::ErrorKind::NotConnected.into()),
+            Pushed::TooSlow => {
+                warn!("Disconnecting {}, it doesn't read what is sent to it", uuid);
+                if let Some(observer) = &self.observer {
+                    observer.too_slow();
+                }
+                // It won't read what is queued anyway, and whoever is sending doesn't wait for the rest to be written
+                outbox.clear();
+                if let Some(connection) = self.remove(uuid, Some(DisconnectReason::TooSlow)).await {
+                    smol::spawn(finish(connection)).detach();
+                }
+                Err(io::Error::other("The peer is too slow to read"))
+            }
+            pushed => Ok(pushed),
+        }
+    }
+
+    /// Fails if the TLS handshake does
+    pub async fn connection(&self, tcp: TcpStream) -> Result<Uuid, std::io::Error> {
+        let stream = match &self.tls {
+            Some(tls) => tls.accept(tcp.clone()).await?,
+            None => Stream::Plain(tcp.clone()),
+        };
+        let (read, write) = smol::io::split(stream);
+        let outbox = Arc::new(Outbox::new(self.max_queue, self.queue_grace));
+        let (requests, incoming) = smol::channel::bounded(READ_AHEAD);
+        let liveness = Arc::new(Mutex::new(Liveness::new()));
+        let mut streams = self.streams.write().await;
         let mut uuid = Uuid::new_v4();
-        while self.streams.read().await.contains_key(&uuid) {
+        while streams.contains_key(&uuid) {
             uuid = Uuid::new_v4();
         }
-        self.streams.write().await.insert(uuid.clone(), Arc::new(RwLock::new(tcp)));
-        uuid
+        smol::spawn(
+            self.clone()
+                .read(uuid, read, requests, outbox.clone(), liveness.clone()),
+        )
+        .detach();
+        streams.insert(
+            uuid,
+            Connection {
+                outbox: outbox.clone(),
+                incoming,
+                writer: smol::spawn(write_frames(
+                    write,
+                    outbox,
+                    tcp.clone(),
+                    self.observer.clone(),
+                )),
+                tcp,
+                liveness,
+            },
+        );
+        Ok(uuid)
     }
 
-    pub async fn recv(&self, uuid: &Uuid) -> Result<Request, std::io::Error> {
-        match self.get(uuid).await.clone() {
-            Some(v) => {
-                let mut should_close = false;
-                let r = match v.write().await.recv_val().await {
-                    Ok(ServerRequest::Close) => {
-                        should_close = true;
-                        Err(std::io::ErrorKind::NotConnected.into())
-                    }
-                    Ok(ServerRequest::Message(x)) => Ok(x),
-                    Err(e) => Err(e),
-                };
+    /// Reads the requests of a connection until it ends, answering the pongs on the way
+    async fn read(
+        self,
+        uuid: Uuid,
+        mut stream: ReadHalf<Stream>,
+        requests: Sender<io::Result<Message>>,
+        outbox: Arc<Outbox>,
+        liveness: Arc<Mutex<Liveness>>,
+    ) {
+        let mut reader = FrameReader::new(self.max_frame);
+        loop {
+            let res = self
+                .next_request(&uuid, &mut stream, &mut reader, &outbox, &liveness)
+                .await;
+            let last = match &res {
+                Ok(_) => false,
+                // The frame was skipped, the next one can be read
+                Err(e) => e.kind() != io::ErrorKind::InvalidData,
+            };
+            let res = res.map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => io::ErrorKind::NotConnected.into(),
+                _ => e,
+            });
+            if requests.send(res).await.is_err() || last {
+                break;
+            }
+        }
+        drop(requests);
+        self.close(&uuid).await;
+    }
 
-                if should_close {
-                    self.remove(uuid).await;
+    async fn next_request(
+        &self,
+        uuid: &Uuid,
+        stream: &mut ReadHalf<Stream>,
+        reader: &mut FrameReader,
+        outbox: &Outbox,
+        liveness: &Mutex<Liveness>,
+    ) -> io::Result<Message> {
+        loop {
+            // A frame that started arriving has to finish in time, until then the connection is pinged now and then
+            let wait = match reader.started() {
+                Some(started) => self.timeouts.frame.saturating_sub(started.elapsed()),
+                None => self
+                    .timeouts
+                    .ping_interval
+                    .saturating_sub(liveness.lock().unwrap().last_ping.elapsed()),
+            };
+            let res = match timeout(wait, reader.read::<_, ServerRequest>(stream)).await {
+                Some(res) => res,
+                None => {
+                    match reader.started() {
+                        Some(started) if started.elapsed() >= self.timeouts.frame => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "The frame didn't arrive in time",
+                            ))
+                        }
+                        Some(_) => continue,
+                        None => (),
+                    }
+                    if liveness.lock().unwrap().last_seen.elapsed() >= self.timeouts.idle {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "The peer stopped answering",
+                        ));
+                    }
+                    let ping = liveness.lock().unwrap().ping();
+                    self.push(uuid, outbox, codec::encode(&ClientMessage::Ping(ping))?, Kind::Other).await?;
+                    continue;
+                }
+            };
+            liveness.lock().unwrap().last_seen = Instant::now();
+            match res {
+                Ok((ServerRequest::Pong(ping), _)) => liveness.lock().unwrap().pong(ping),
+                Ok((ServerRequest::Close, _)) => return Err(io::ErrorKind::NotConnected.into()),
+                Ok((ServerRequest::Message(x), size)) => {
+                    if let Some(observer) = &self.observer {
+                        observer.frame_received(size);
+                    }
+                    return Ok(x);
+                }
+                Err(e) => {
+                    match &self.observer {
+                        Some(observer) if e.kind() == io::ErrorKind::InvalidData => {
+                            observer.decode_failed()
+                        }
+                        _ => (),
+                    }
+                    return Err(e);
                 }
-                r
             }
-            None => Err(std::io::ErrorKind::NotConnected.into()),
         }
     }
 
+    /// Waits for the next request. Connections that close, stop answering or break a frame are closed and forgotten.
+    pub async fn recv(&self, uuid: &Uuid) -> Result<Message, std::io::Error> {
+        let incoming = match self.streams.read().await.get(uuid) {
+            Some(c) => c.incoming.clone(),
+            None => return Err(io::ErrorKind::NotConnected.into()),
+        };
+        match incoming.recv().await {
+            Ok(res) => res,
+            Err(_) => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    /// Queues the reply for the connection's writer, a state of a room replaces the ones that may still be queued.
+    /// Connections that don't keep up are disconnected.
     pub async fn send(&self, uuid: &Uuid, reply: &Reply) -> Result<(), std::io::Error> {
-        match self.get(uuid).await.clone() {
-            Some(v) => v.write().await.send_val(reply).await,
-            None => Err(std::io::ErrorKind::NotConnected.into()),
+        let outbox = match self.streams.read().await.get(uuid) {
+            Some(c) => c.outbox.clone(),
+            None => return Err(io::ErrorKind::NotConnected.into()),
+        };
+        let kind = match reply {
+            Reply::Event(Event::State { room, .. }) => Kind::State(*room),
+            Reply::Event(Event::StateDelta { room, .. }) => Kind::Delta(*room),
+            _ => Kind::Other,
+        };
+        let frame = codec::encode(&ClientMessage::Message(Cow::Borrowed(reply)))?;
+        trace!("Frame of {} bytes", frame.len());
+        let pushed = self.push(uuid, &outbox, frame, kind).await?;
+        if pushed == Pushed::Replaced {
+            if let Some(observer) = &self.observer {
+                observer.state_replaced();
+            }
         }
+        Ok(())
+    }
+
+    /// The time the last ping took to be answered
+    pub async fn latency(&self, uuid: &Uuid) -> Option<Duration> {
+        self.streams
+            .read()
+            .await
+            .get(uuid)?
+            .liveness
+            .lock()
+            .unwrap()
+            .latency
     }
 
     pub async fn peer_addr(&self, uuid: &Uuid) -> std::io::Result<std::net::SocketAddr> {
-        match self.get(uuid).await.clone() {
-            Some(v) => v.read().await.peer_addr(),
+        match self.streams.read().await.get(uuid) {
+            Some(c) => c.tcp.peer_addr(),
             None => Err(std::io::ErrorKind::NotConnected.into()),
         }
     }
 }
 
+/// Waits for the writer, unless the peer stopped reading
+async fn finish(connection: Connection) {
+    if timeout(CLOSE_TIMEOUT, connection.writer).await.is_none() {
+        connection.tcp.shutdown(std::net::Shutdown::Both).ok();
+    }
+}
+
+/// Writes the queued frames until the queue is closed, then ends the connection.
+/// Frames count as sent once they are written, not when they are queued, as they may be replaced or dropped.
+async fn write_frames(
+    mut stream: WriteHalf<Stream>,
+    outbox: Arc<Outbox>,
+    tcp: TcpStream,
+    observer: Option<Arc<dyn Observer>>,
+) {
+    loop {
+        match outbox.pop() {
+            Some(frame) => {
+                if let Err(e) = stream.write_all(&frame).await {
+                    trace!("Unable to write: {}", e);
+                    break;
+                }
+                if let Some(observer) = &observer {
+                    observer.frame_sent(frame.len() - 4);
+                }
+            }
+            // Once there's nothing else to write
+            None => {
+                if stream.flush().await.is_err() || !outbox.wait().await {
+                    break;
+                }
+            }
+        }
+    }
+    // Tells the peer when it is TLS
+    timeout(CLOSE_TIMEOUT, stream.close()).await;
+    // Wakes up the reader
+    tcp.shutdown(std::net::Shutdown::Both).ok();
+}
+
+impl Default for ServerProtocol {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Clone for ServerProtocol {
     fn clone(&self) -> Self {
         Self {
             streams: self.streams.clone(),
+            tls: self.tls.clone(),
+            observer: self.observer.clone(),
+            timeouts: self.timeouts,
+            max_frame: self.max_frame,
+            max_queue: self.max_queue,
+            queue_grace: self.queue_grace,
         }
     }
 }
 
+/// The pings of the server are answered while receiving, so clients should keep doing it
 pub struct ClientProtocolStream {
-    tcp: TcpStream,
+    tcp: Framed<Stream>,
+    timeouts: Timeouts,
 }
 
 impl ClientProtocolStream {
     pub fn new(tcp: TcpStream) -> Self {
-        Self { tcp }
+        Self {
+            tcp: Framed::new(Stream::Plain(tcp), DEFAULT_MAX_FRAME),
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    /// The server's certificate has to be for that name, a host name or an IP address, unless it's pinned
+    pub async fn new_tls(
+        tcp: TcpStream,
+        tls: &ClientTls,
+        name: &str,
+    ) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            tcp: Framed::new(tls.connect(tcp, name).await?, DEFAULT_MAX_FRAME),
+            timeouts: Timeouts::default(),
+        })
+    }
+
+    /// Larger replies are an error that leaves the stream unusable
+    pub fn with_max_frame(mut self, max_frame: usize) -> Self {
+        self.tcp.reader = codec::FrameReader::new(max_frame);
+        self
     }
 
+    /// Only the idle and frame timeouts apply, the server is the one pinging
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Fails with `TimedOut` when the server sends nothing, not even a ping, for the idle timeout
     pub async fn recv(&mut self) -> Result<Reply, std::io::Error> {
-        self.tcp.recv_val().await
+        loop {
+            let wait = match self.tcp.reader.started() {
+                Some(started) => self.timeouts.frame.saturating_sub(started.elapsed()),
+                None => self.timeouts.idle,
+            };
+            let message = match timeout(wait, self.tcp.recv::<ClientMessage>()).await {
+                Some(res) => res?.0,
+                None if self
+                    .tcp
+                    .reader
+                    .started()
+                    .is_some_and(|x| x.elapsed() < self.timeouts.frame) =>
+                {
+                    continue
+                }
+                None => return Err(io::ErrorKind::TimedOut.into()),
+            };
+            match message {
+                ClientMessage::Ping(ping) => {
+                    self.tcp.send(&ServerRequest::Pong(ping)).await?;
+                }
+                ClientMessage::Message(reply) => return Ok(reply.into_owned()),
+            }
+        }
     }
 
-    pub async fn send(&mut self, req: Request) -> Result<(), std::io::Error> {
-        self.tcp.send_val(&ServerRequest::Message(req)).await
+    pub async fn send(&mut self, message: impl Into<Message>) -> Result<(), std::io::Error> {
+        self.tcp.send(&ServerRequest::Message(message.into())).await.map(|_| ())
     }
 }
 
@@ -120,91 +997,16 @@ impl Drop for ClientProtocolStream {
     #[instrument(skip(self), name = "drop protocol client")]
     fn drop(&mut self) {
         trace!("Dropping client");
-        if let Ok(()) = smol::block_on(self.tcp.send_val(&ServerRequest::Close)) {}
-    }
-}
-
-use async_trait::async_trait;
-
-#[async_trait]
-trait RecvValue<T>: AsyncRead + Unpin + Sized
-where
-    T: Sized,
-    for<'a> T: Deserialize<'a>,
-    T: 'static,
-{
-    async fn recv_val(&mut self) -> Result<T, std::io::Error> {
-        let mut message_size = [0, 0, 0, 0];
-        fill(&mut message_size, self).await?;
-        // println!("{:?}", message_size);
-        let message_size: u32 = u32::from_le_bytes(message_size);
-        // println!("Message is {} bytes", message_size);
-        let mut message = vec![0u8; message_size as usize];
-
-        fill(&mut message, self).await?;
-        // println!("{:?}", message);
-        Ok(bincode::deserialize_from(message.as_slice()).expect("Malformed message"))
-    }
-}
-
-async fn fill<R: AsyncRead + Unpin>(buf: &mut [u8], reader: &mut R) -> Result<(), std::io::Error> {
-    (async {
-        let mut reader = reader.take(buf.len() as u64);
-        let mut intermidiate_buffer = vec![0u8; buf.len()];
-        let mut read = 0;
-        while read < buf.len() - 1 {
-            let read_bytes = reader.read(&mut intermidiate_buffer).await?;
-            for i in read..read_bytes {
-                buf[i] = intermidiate_buffer[i - read];
-            }
-            read += read_bytes;
-
-            smol::future::yield_now().await;
-        }
-        Ok(())
-    })
-    .or(async {
-        smol::Timer::after(std::time::Duration::from_secs(5)).await;
-        Err(std::io::ErrorKind::TimedOut.into())
-    })
-    .await
-}
-
-impl<T> RecvValue<T> for TcpStream
-where
-    T: Sized,
-    for<'a> T: Deserialize<'a>,
-    T: 'static,
-{
-}
-
-#[async_trait]
-trait SendValue<T>: AsyncWrite + Unpin
-where
-    T: Send + Sync + Sized,
-    T: Serialize,
-    T: std::fmt::Debug,
-    T: 'static,
-{
-    #[instrument(skip(self), name = "proto_send")]
-    async fn send_val(&mut self, val: &T) -> Result<(), std::io::Error> {
-        let message = bincode::serialize(val).unwrap();
-        let message_size = message.len() as u32;
-        assert!(message_size <= 1 << 24);
-        trace!("Message is {} bytes", message_size);
-        trace!("{:?}", message);
-        let message_size = message_size.to_le_bytes();
-        self.write_all(&message_size).await?;
-        self.write_all(&message).await?;
-        Ok(())
+        smol::block_on(self.tcp.send(&ServerRequest::Close)).ok();
     }
 }
 
-impl<T> SendValue<T> for TcpStream
-where
-    T: Send + Sync + Sized,
-    T: Serialize,
-    T: std::fmt::Debug,
-    T: 'static,
-{
+/// `None` if the future takes longer than that, in which case it's cancelled
+async fn timeout<F: Future>(wait: Duration, future: F) -> Option<F::Output> {
+    async { Some(future.await) }
+        .or(async {
+            smol::Timer::after(wait).await;
+            None
+        })
+        .await
 }
@@ -0,0 +1,152 @@
+//! The frames waiting for the writer of a connection
+
+use smol::channel::{Receiver, Sender};
+use uuid::Uuid;
+
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// What a frame is, as far as replacing it goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    /// The whole state of a room, it replaces the states and deltas of the room that are still queued
+    State(Uuid),
+    Delta(Uuid),
+    Other,
+}
+
+impl Kind {
+    fn room(self) -> Option<Uuid> {
+        match self {
+            Kind::State(room) | Kind::Delta(room) => Some(room),
+            Kind::Other => None,
+        }
+    }
+}
+
+struct Queued {
+    frame: Vec<u8>,
+    kind: Kind,
+}
+
+#[derive(Default)]
+struct Frames {
+    queued: VecDeque<Queued>,
+    bytes: usize,
+    /// Since when more than the limit is queued
+    over_since: Option<Instant>,
+    closed: bool,
+}
+
+/// What became of a pushed frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Pushed {
+    Queued,
+    /// It took the place of older states or deltas of the same room
+    Replaced,
+    /// The queue has been over the limit for too long, or is way over it
+    TooSlow,
+    Closed,
+}
+
+/// A queue that holds up to a limit of bytes, only for a while longer
+pub(crate) struct Outbox {
+    frames: Mutex<Frames>,
+    /// Wakes up the writer, it's closed with the queue
+    wake: Sender<()>,
+    woken: Receiver<()>,
+    limit: usize,
+    grace: Duration,
+}
+
+impl Outbox {
+    pub(crate) fn new(limit: usize, grace: Duration) -> Self {
+        let (wake, woken) = smol::channel::bounded(1);
+        Self {
+            frames: Default::default(),
+            wake,
+            woken,
+            limit,
+            grace,
+        }
+    }
+
+    pub(crate) fn push(&self, frame: Vec<u8>, kind: Kind) -> Pushed {
+        let mut frames = self.frames.lock().unwrap();
+        if frames.closed {
+            return Pushed::Closed;
+        }
+        let mut pushed = Pushed::Queued;
+        if let Kind::State(room) = kind {
+            // Dropped rather than updated in place, so the state comes after the events sent before it
+            let before = frames.queued.len();
+            frames.queued.retain(|x| x.kind.room() != Some(room));
+            if frames.queued.len() < before {
+                frames.bytes = frames.queued.iter().map(|x| x.frame.len()).sum();
+                pushed = Pushed::Replaced;
+            }
+        }
+        frames.bytes += frame.len();
+        frames.queued.push_back(Queued { frame, kind });
+        if frames.bytes > self.limit {
+            let since = *frames.over_since.get_or_insert_with(Instant::now);
+            // A single frame may be larger than the limit on its own
+            let way_over = frames.bytes > 2 * self.limit && frames.queued.len() > 1;
+            if way_over || since.elapsed() >= self.grace {
+                return Pushed::TooSlow;
+            }
+        } else {
+            frames.over_since = None;
+        }
+        drop(frames);
+        self.wake.try_send(()).ok();
+        pushed
+    }
+
+    /// Drops what is queued
+    pub(crate) fn clear(&self) {
+        let mut frames = self.frames.lock().unwrap();
+        frames.queued.clear();
+        frames.bytes = 0;
+        frames.over_since = None;
+    }
+
+    /// Closes the queue after the frame, whatever the limit
+    pub(crate) fn last(&self, frame: Vec<u8>) {
+        let mut frames = self.frames.lock().unwrap();
+        frames.bytes += frame.len();
+        frames.queued.push_back(Queued {
+            frame,
+            kind: Kind::Other,
+        });
+        frames.closed = true;
+        drop(frames);
+        self.wake.close();
+    }
+
+    /// What is queued is still written
+    pub(crate) fn close(&self) {
+        self.frames.lock().unwrap().closed = true;
+        self.wake.close();
+    }
+
+    pub(crate) fn pop(&self) -> Option<Vec<u8>> {
+        let mut frames = self.frames.lock().unwrap();
+        let next = frames.queued.pop_front()?;
+        frames.bytes -= next.frame.len();
+        if frames.bytes <= self.limit {
+            frames.over_since = None;
+        }
+        Some(next.frame)
+    }
+
+    /// Waits for a frame, false once the queue is closed and empty
+    pub(crate) async fn wait(&self) -> bool {
+        self.woken.recv().await.ok();
+        let frames = self.frames.lock().unwrap();
+        !(frames.closed && frames.queued.is_empty())
+    }
+}
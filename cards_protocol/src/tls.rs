@@ -0,0 +1,247 @@
+use futures_rustls::{
+    client, rustls,
+    rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{ring, CryptoProvider},
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+        DigitallySignedStruct, SignatureScheme,
+    },
+    server, TlsAcceptor, TlsConnector,
+};
+use smol::{net::TcpStream, prelude::*};
+
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Peers that take longer than this to finish the handshake are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection, encrypted or not
+pub(crate) enum Stream {
+    Plain(TcpStream),
+    Server(Box<server::TlsStream<TcpStream>>),
+    Client(Box<client::TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Self::Server(s) => Pin::new(s).poll_read(cx, buf),
+            Self::Client(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Self::Server(s) => Pin::new(s).poll_write(cx, buf),
+            Self::Client(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(s) => Pin::new(s).poll_flush(cx),
+            Self::Server(s) => Pin::new(s).poll_flush(cx),
+            Self::Client(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(s) => Pin::new(s).poll_close(cx),
+            Self::Server(s) => Pin::new(s).poll_close(cx),
+            Self::Client(s) => Pin::new(s).poll_close(cx),
+        }
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn invalid(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Every certificate of a PEM file
+fn load_certs<P: AsRef<Path>>(path: P) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No certificates in the file",
+        ));
+    }
+    Ok(certs)
+}
+
+/// The first private key of a PEM file
+fn load_key<P: AsRef<Path>>(path: P) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No private key in the file"))
+}
+
+/// The certificate chain and key the server shows its clients
+#[derive(Clone)]
+pub struct ServerTls {
+    acceptor: TlsAcceptor,
+}
+
+impl ServerTls {
+    /// The certificate file holds the server certificate followed by any intermediates
+    pub fn from_pem_files<P: AsRef<Path>, Q: AsRef<Path>>(cert: P, key: Q) -> io::Result<Self> {
+        let config = rustls::ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?
+            .with_no_client_auth()
+            .with_single_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(invalid)?;
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    pub(crate) async fn accept(&self, tcp: TcpStream) -> io::Result<Stream> {
+        let stream = self
+            .acceptor
+            .accept(tcp)
+            .or(async {
+                smol::Timer::after(HANDSHAKE_TIMEOUT).await;
+                Err(io::ErrorKind::TimedOut.into())
+            })
+            .await?;
+        Ok(Stream::Server(Box::new(stream)))
+    }
+}
+
+/// Which servers a client trusts
+#[derive(Clone)]
+pub struct ClientTls {
+    connector: TlsConnector,
+}
+
+impl ClientTls {
+    /// Trusts servers with a certificate for their name issued by one of the CAs of the file,
+    /// like a local CA made for tests
+    pub fn with_roots<P: AsRef<Path>>(ca: P) -> io::Result<Self> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in load_certs(ca)? {
+            roots.add(cert).map_err(invalid)?;
+        }
+        let config = rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self::from_config(config))
+    }
+
+    /// Only trusts servers showing one of the certificates of the file, whatever their name and issuer.
+    /// Meant for servers with a self-signed certificate
+    pub fn pinned<P: AsRef<Path>>(certs: P) -> io::Result<Self> {
+        let verifier = Pinned {
+            certs: load_certs(certs)?,
+            provider: provider(),
+        };
+        let config = rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        Ok(Self::from_config(config))
+    }
+
+    fn from_config(config: rustls::ClientConfig) -> Self {
+        Self {
+            connector: TlsConnector::from(Arc::new(config)),
+        }
+    }
+
+    /// The name is the one the certificate has to be for, a host name or an IP address
+    pub(crate) async fn connect(&self, tcp: TcpStream, name: &str) -> io::Result<Stream> {
+        let name = ServerName::try_from(name.to_string()).map_err(invalid)?;
+        let stream = self.connector.connect(name, tcp).await?;
+        Ok(Stream::Client(Box::new(stream)))
+    }
+}
+
+#[derive(Debug)]
+struct Pinned {
+    certs: Vec<CertificateDer<'static>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.certs.iter().any(|c| c.as_ref() == end_entity.as_ref()) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
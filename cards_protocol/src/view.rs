@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PileId {
+    /// One of the piles shared by all the players
+    Common(usize),
+    /// One of the piles of the player making the request
+    Own(usize),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Card {
+    pub image: String,
+    pub properties: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PileView {
+    pub face_down: bool,
+    /// Hidden cards are `None`, but still take a place in the pile
+    pub cards: Vec<Option<Card>>,
+}
+
+/// What one connection is allowed to see of a running game
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GameView {
+    /// The seat of the viewer, `None` for spectators
+    pub seat: Option<usize>,
+    pub piles: Vec<PileView>,
+    /// The piles of every seat, in seat order
+    pub player_piles: Vec<Vec<PileView>>,
+    /// The seat whose turn it is, if the game uses turns
+    pub turn: Option<usize>,
+    /// Only shown to the seat that has to answer it
+    pub prompt: Option<Prompt>,
+}
+
+/// A question the game asks to one seat
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Prompt {
+    pub seat: usize,
+    pub question: String,
+    pub options: Vec<String>,
+}
@@ -0,0 +1,142 @@
+//! Frames read back through arbitrary splits of the stream, like the ones TCP makes
+
+use cards_protocol::codec::{encode, FrameReader};
+use proptest::prelude::*;
+use smol::{future, io::AsyncRead};
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+type Value = (u32, String, Vec<u8>);
+
+/// Gives out the data in chunks of the given sizes, in turns, and if `stall` is set, is pending before each one
+struct Splits {
+    data: Vec<u8>,
+    pos: usize,
+    chunks: Vec<usize>,
+    next: usize,
+    stall: bool,
+    stalled: bool,
+}
+
+impl Splits {
+    fn new(data: Vec<u8>, chunks: Vec<usize>, stall: bool) -> Self {
+        Self {
+            data,
+            pos: 0,
+            chunks,
+            next: 0,
+            stall,
+            stalled: false,
+        }
+    }
+}
+
+impl AsyncRead for Splits {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.stall && !self.stalled {
+            self.stalled = true;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        self.stalled = false;
+        let chunk = self.chunks[self.next % self.chunks.len()];
+        self.next += 1;
+        let len = chunk.min(buf.len()).min(self.data.len() - self.pos);
+        buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+        self.pos += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+fn frames(values: &[Value]) -> Vec<u8> {
+    values.iter().flat_map(|v| encode(v).unwrap()).collect()
+}
+
+/// Values with up to so many bytes besides the string
+fn value(bytes: usize) -> impl Strategy<Value = Value> {
+    (
+        any::<u32>(),
+        ".{0,40}",
+        prop::collection::vec(any::<u8>(), 0..bytes),
+    )
+}
+
+proptest! {
+    #[test]
+    fn reads_every_frame_whatever_the_splits(
+        values in prop::collection::vec(value(12_000), 0..6),
+        chunks in prop::collection::vec(1usize..5_000, 1..16),
+    ) {
+        let mut stream = Splits::new(frames(&values), chunks, false);
+        let mut reader = FrameReader::default();
+        for expected in &values {
+            let (read, _) = future::block_on(reader.read::<_, Value>(&mut stream)).unwrap();
+            prop_assert_eq!(&read, expected);
+        }
+        let end = future::block_on(reader.read::<_, Value>(&mut stream));
+        prop_assert_eq!(end.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn loses_nothing_when_reads_are_cancelled(
+        values in prop::collection::vec(value(200), 1..8),
+        chunks in prop::collection::vec(1usize..64, 1..16),
+    ) {
+        let mut stream = Splits::new(frames(&values), chunks, true);
+        let mut reader = FrameReader::default();
+        for expected in &values {
+            // Each pending read is dropped and started over
+            let read = loop {
+                if let Some(res) = future::block_on(future::poll_once(reader.read::<_, Value>(&mut stream))) {
+                    break res.unwrap().0;
+                }
+            };
+            prop_assert_eq!(&read, expected);
+        }
+    }
+
+    #[test]
+    fn skips_frames_that_dont_decode(
+        garbage in prop::collection::vec(any::<u8>(), 0..3),
+        value in value(200),
+        chunks in prop::collection::vec(1usize..64, 1..16),
+    ) {
+        let mut data = (garbage.len() as u32).to_le_bytes().to_vec();
+        data.extend(&garbage);
+        data.extend(encode(&value).unwrap());
+        let mut stream = Splits::new(data, chunks, false);
+        let mut reader = FrameReader::default();
+        let invalid = future::block_on(reader.read::<_, Value>(&mut stream));
+        prop_assert_eq!(invalid.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let (read, _) = future::block_on(reader.read::<_, Value>(&mut stream)).unwrap();
+        prop_assert_eq!(read, value);
+    }
+}
+
+#[test]
+fn rejects_frames_over_the_limit() {
+    let value: Value = (1, "big".to_string(), vec![0; 2_000]);
+    let mut stream = Splits::new(frames(&[value]), vec![10], false);
+    let mut reader = FrameReader::new(1_000);
+    let res = future::block_on(reader.read::<_, Value>(&mut stream));
+    assert_eq!(res.unwrap_err().kind(), io::ErrorKind::Other);
+    // It gives up as soon as it has the length
+    assert_eq!(stream.pos, 10);
+}
+
+#[test]
+fn fails_on_a_truncated_frame() {
+    let mut data = encode(&(7u32, "cut".to_string(), vec![1u8; 100])).unwrap();
+    data.truncate(50);
+    let mut stream = Splits::new(data, vec![7], false);
+    let res = future::block_on(FrameReader::default().read::<_, Value>(&mut stream));
+    assert_eq!(res.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+}
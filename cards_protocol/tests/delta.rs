@@ -0,0 +1,129 @@
+//! Views rebuilt from their diffs
+
+use cards_protocol::{Card, Change, GameView, PileAt, PileView, Prompt};
+use proptest::prelude::*;
+
+fn card() -> impl Strategy<Value = Option<Card>> {
+    prop::option::of(
+        prop::sample::select(vec!["a", "b", "c", "d"]).prop_map(|image| Card {
+            image: image.to_string(),
+            properties: Default::default(),
+        }),
+    )
+}
+
+fn pile() -> impl Strategy<Value = PileView> {
+    (any::<bool>(), prop::collection::vec(card(), 0..8))
+        .prop_map(|(face_down, cards)| PileView { face_down, cards })
+}
+
+/// A view with that many common piles and seats with that many piles each
+fn view(common: usize, seats: Vec<usize>) -> impl Strategy<Value = GameView> {
+    let player_piles: Vec<_> = seats
+        .into_iter()
+        .map(|piles| prop::collection::vec(pile(), piles))
+        .collect();
+    (
+        prop::collection::vec(pile(), common),
+        player_piles,
+        prop::option::of(0..4usize),
+        any::<bool>(),
+    )
+        .prop_map(|(piles, player_piles, turn, prompted)| GameView {
+            seat: Some(0),
+            piles,
+            player_piles,
+            turn,
+            prompt: if prompted {
+                Some(Prompt {
+                    seat: 0,
+                    question: "?".to_string(),
+                    options: vec!["yes".to_string(), "no".to_string()],
+                })
+            } else {
+                None
+            },
+        })
+}
+
+/// Two views of the same piles
+fn views() -> impl Strategy<Value = (GameView, GameView)> {
+    (0..4usize, prop::collection::vec(0..3usize, 0..4))
+        .prop_flat_map(|(common, seats)| (view(common, seats.clone()), view(common, seats)))
+}
+
+proptest! {
+    #[test]
+    fn applying_the_diff_gives_the_newer_view((old, new) in views()) {
+        if let Some(changes) = old.diff(&new) {
+            let mut view = old.clone();
+            prop_assert!(view.apply(&changes));
+            prop_assert_eq!(view, new);
+        }
+    }
+
+    #[test]
+    fn moving_a_card_is_a_move(
+        (mut old, _) in views().prop_filter("two piles", |(v, _)| v.piles.len() >= 2),
+        card in card(),
+    ) {
+        // Tops up the piles so the diff is smaller than the view
+        for pile in &mut old.piles {
+            pile.cards.extend(vec![None; 20]);
+        }
+        old.piles[0].cards.push(card);
+        let mut new = old.clone();
+        let moved = new.piles[0].cards.pop().unwrap();
+        new.piles[1].cards.push(moved);
+        let changes = old.diff(&new).unwrap();
+        prop_assert_eq!(changes.len(), 1);
+        let is_move = matches!(
+            changes[0],
+            Change::Moved { from: PileAt::Common(0), to: PileAt::Common(1), count: 1, cards: None, .. }
+        );
+        prop_assert!(is_move);
+        let mut view = old.clone();
+        prop_assert!(view.apply(&changes));
+        prop_assert_eq!(view, new);
+    }
+}
+
+#[test]
+fn other_piles_cant_be_diffed() {
+    let old = GameView {
+        seat: Some(0),
+        piles: vec![],
+        player_piles: vec![vec![]],
+        turn: None,
+        prompt: None,
+    };
+    let new = GameView {
+        player_piles: vec![vec![], vec![]],
+        ..old.clone()
+    };
+    assert_eq!(old.diff(&new), None);
+}
+
+#[test]
+fn changes_that_dont_fit_are_refused() {
+    let mut view = GameView {
+        seat: None,
+        piles: vec![PileView {
+            face_down: true,
+            cards: vec![None],
+        }],
+        player_piles: vec![],
+        turn: None,
+        prompt: None,
+    };
+    assert!(!view.apply(&[Change::Spliced {
+        pile: PileAt::Common(0),
+        start: 1,
+        removed: 1,
+        cards: vec![],
+    }]));
+    assert!(!view.apply(&[Change::Flipped {
+        pile: PileAt::Seat(0, 0),
+        face_down: false,
+    }]));
+}
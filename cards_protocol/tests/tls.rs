@@ -0,0 +1,126 @@
+//! Connections over TLS, with certificates from a local test CA or self-signed and pinned
+
+use cards_protocol::{
+    ClientProtocolStream, ClientTls, Message, Reply, Request, ServerProtocol, ServerTls,
+};
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+use smol::net::{TcpListener, TcpStream};
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A folder of its own for each test
+fn folder() -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let folder = std::env::temp_dir().join(format!(
+        "cards_tls_{}_{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::create_dir_all(&folder).unwrap();
+    folder
+}
+
+/// A test CA and a certificate it issued for localhost, as `ca.pem`, `cert.pem` and `key.pem`
+fn issue(folder: &Path) {
+    let ca_key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "cards test CA");
+    let ca = params.self_signed(&ca_key).unwrap();
+
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec!["localhost".to_string()])
+        .unwrap()
+        .signed_by(&key, &ca, &ca_key)
+        .unwrap();
+    fs::write(folder.join("ca.pem"), ca.pem()).unwrap();
+    fs::write(folder.join("cert.pem"), cert.pem()).unwrap();
+    fs::write(folder.join("key.pem"), key.serialize_pem()).unwrap();
+}
+
+/// A self-signed certificate as `cert.pem` and `key.pem`
+fn self_signed(folder: &Path) {
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec!["cards.invalid".to_string()])
+        .unwrap()
+        .self_signed(&key)
+        .unwrap();
+    fs::write(folder.join("cert.pem"), cert.pem()).unwrap();
+    fs::write(folder.join("key.pem"), key.serialize_pem()).unwrap();
+}
+
+/// Connects to a server using the certificate of the folder, sends a request and waits for the reply
+fn exchange(folder: &Path, tls: &ClientTls, name: &str) -> std::io::Result<Reply> {
+    let server_tls =
+        ServerTls::from_pem_files(folder.join("cert.pem"), folder.join("key.pem")).unwrap();
+    smol::block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = smol::spawn(async move {
+            let server = ServerProtocol::with_tls(server_tls);
+            let (tcp, _) = listener.accept().await.unwrap();
+            let uuid = server.connection(tcp).await?;
+            match server.recv(&uuid).await? {
+                Message::Request(Request::Games) => server.send(&uuid, &Reply::Ok).await,
+                req => panic!("Unexpected request {:?}", req),
+            }
+        });
+        let tcp = TcpStream::connect(addr).await?;
+        let mut client = ClientProtocolStream::new_tls(tcp, tls, name).await?;
+        client.send(Request::Games).await?;
+        let reply = client.recv().await;
+        server.await?;
+        reply
+    })
+}
+
+#[test]
+fn trusts_the_test_ca() {
+    let folder = folder();
+    issue(&folder);
+    let tls = ClientTls::with_roots(folder.join("ca.pem")).unwrap();
+    assert!(matches!(
+        exchange(&folder, &tls, "localhost"),
+        Ok(Reply::Ok)
+    ));
+}
+
+#[test]
+fn checks_the_name() {
+    let folder = folder();
+    issue(&folder);
+    let tls = ClientTls::with_roots(folder.join("ca.pem")).unwrap();
+    assert!(exchange(&folder, &tls, "example.com").is_err());
+}
+
+#[test]
+fn rejects_other_cas() {
+    let folder = folder();
+    issue(&folder);
+    let other = self::folder();
+    issue(&other);
+    let tls = ClientTls::with_roots(other.join("ca.pem")).unwrap();
+    assert!(exchange(&folder, &tls, "localhost").is_err());
+}
+
+#[test]
+fn pins_self_signed_certificates() {
+    let folder = folder();
+    self_signed(&folder);
+    let tls = ClientTls::pinned(folder.join("cert.pem")).unwrap();
+    assert!(matches!(
+        exchange(&folder, &tls, "localhost"),
+        Ok(Reply::Ok)
+    ));
+
+    let other = self::folder();
+    self_signed(&other);
+    let tls = ClientTls::pinned(other.join("cert.pem")).unwrap();
+    assert!(exchange(&folder, &tls, "localhost").is_err());
+}
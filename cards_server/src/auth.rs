@@ -0,0 +1,289 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64, Engine};
+use cards_protocol::Credentials;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tracing::warn;
+
+const HASH_ROUNDS: u32 = 100_000;
+const SALT_LENGTH: usize = 16;
+const KEY_LENGTH: usize = 32;
+/// Tokens are good for a week
+const TOKEN_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Who a connection authenticated as
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Identity {
+    /// Accounts log in with a name, it is locked for the rest of the connection
+    pub name: Option<String>,
+    pub admin: bool,
+}
+
+/// One way of checking the credentials of the handshake
+pub trait Authenticator: Send + Sync {
+    /// `None` if the credentials aren't of this kind or are wrong
+    fn authenticate(&self, credentials: &Credentials) -> Option<Identity>;
+}
+
+/// Lets in anyone who knows the password shared by the server
+pub struct ServerPassword(pub String);
+
+impl Authenticator for ServerPassword {
+    fn authenticate(&self, credentials: &Credentials) -> Option<Identity> {
+        match credentials {
+            Credentials::ServerPassword(password)
+                if constant_time_eq(password.as_bytes(), self.0.as_bytes()) =>
+            {
+                Some(Identity::default())
+            }
+            _ => None,
+        }
+    }
+}
+
+struct Account {
+    admin: bool,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+/// Accounts kept in a file, one per line as `name:role:salt:hash`.
+/// The role is `admin` or `player`, the hash is PBKDF2-HMAC-SHA256 of the password, both it and the salt in base64.
+/// Empty lines and lines starting with `#` are skipped.
+pub struct Accounts {
+    accounts: HashMap<String, Account>,
+}
+
+impl Accounts {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut accounts = HashMap::new();
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_account(line) {
+                Some((name, account)) => {
+                    accounts.insert(name, account);
+                }
+                None => warn!("Skipping malformed account on line {}", i + 1),
+            }
+        }
+        Ok(Self { accounts })
+    }
+
+    /// The names nobody else can take
+    pub fn names(&self) -> HashSet<String> {
+        self.accounts.keys().cloned().collect()
+    }
+}
+
+impl Authenticator for Accounts {
+    fn authenticate(&self, credentials: &Credentials) -> Option<Identity> {
+        match credentials {
+            Credentials::Account { name, password } => {
+                let account = self.accounts.get(name)?;
+                if constant_time_eq(&hash(password, &account.salt), &account.hash) {
+                    Some(Identity {
+                        name: Some(name.clone()),
+                        admin: account.admin,
+                    })
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+fn parse_account(line: &str) -> Option<(String, Account)> {
+    // The name may contain colons, the rest can't
+    let mut parts = line.rsplitn(4, ':');
+    let hash = BASE64.decode(parts.next()?).ok()?;
+    let salt = BASE64.decode(parts.next()?).ok()?;
+    let admin = match parts.next()? {
+        "admin" => true,
+        "player" => false,
+        _ => return None,
+    };
+    let name = parts.next()?.to_string();
+    Some((name, Account { admin, salt, hash }))
+}
+
+/// The line of the account file for an account with that password
+pub fn account_line(name: &str, password: &str, admin: bool) -> String {
+    let salt: [u8; SALT_LENGTH] = rand::thread_rng().gen();
+    format!(
+        "{}:{}:{}:{}",
+        name,
+        if admin { "admin" } else { "player" },
+        BASE64.encode(salt),
+        BASE64.encode(hash(password, &salt))
+    )
+}
+
+fn hash(password: &str, salt: &[u8]) -> [u8; KEY_LENGTH] {
+    let mut hash = [0; KEY_LENGTH];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, HASH_ROUNDS, &mut hash);
+    hash
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Tokens signed with a key only the server knows, handed out after logging in with an account.
+/// They carry the name and an expiry as `name:expiry`, followed by the signature.
+/// The role is the one the account has when the token is used, and the token stops working with the account.
+pub struct Tokens {
+    key: Vec<u8>,
+    accounts: Arc<Accounts>,
+}
+
+impl Tokens {
+    /// Creates the key the first time, so tokens survive restarts
+    pub fn open<P: AsRef<Path>>(path: P, accounts: Arc<Accounts>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let key = match fs::read(path) {
+            Ok(key) => key,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let key: [u8; KEY_LENGTH] = rand::thread_rng().gen();
+                fs::write(path, key)?;
+                key.to_vec()
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Self { key, accounts })
+    }
+
+    pub fn issue(&self, identity: &Identity) -> Option<String> {
+        let name = identity.name.as_ref()?;
+        let expiry = (SystemTime::now() + TOKEN_LIFETIME)
+            .duration_since(UNIX_EPOCH)
+            .ok()?
+            .as_secs();
+        let payload = format!("{}:{}", name, expiry);
+        Some(format!(
+            "{}.{}",
+            BASE64.encode(&payload),
+            BASE64.encode(self.mac(&payload).finalize().into_bytes())
+        ))
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+impl Authenticator for Tokens {
+    fn authenticate(&self, credentials: &Credentials) -> Option<Identity> {
+        let token = match credentials {
+            Credentials::Token(token) => token,
+            _ => return None,
+        };
+        let (payload, signature) = token.split_once('.')?;
+        let payload = String::from_utf8(BASE64.decode(payload).ok()?).ok()?;
+        self.mac(&payload)
+            .verify_slice(&BASE64.decode(signature).ok()?)
+            .ok()?;
+        let (name, expiry) = payload.rsplit_once(':')?;
+        let expiry = UNIX_EPOCH + Duration::from_secs(expiry.parse().ok()?);
+        if expiry < SystemTime::now() {
+            return None;
+        }
+        let account = self.accounts.accounts.get(name)?;
+        Some(Identity {
+            name: Some(name.to_string()),
+            admin: account.admin,
+        })
+    }
+}
+
+/// The authenticators tried in order.
+/// Without any the server is open, and the handshake can be skipped.
+#[derive(Clone, Default)]
+pub struct Auth {
+    methods: Vec<Arc<dyn Authenticator>>,
+    tokens: Option<Arc<Tokens>>,
+    reserved: HashSet<String>,
+}
+
+impl Auth {
+    pub fn with(mut self, method: impl Authenticator + 'static) -> Self {
+        self.methods.push(Arc::new(method));
+        self
+    }
+
+    /// Account names can only be used by logging in
+    pub fn with_accounts(mut self, accounts: Arc<Accounts>) -> Self {
+        self.reserved.extend(accounts.names());
+        self.methods.push(accounts);
+        self
+    }
+
+    /// Logging in with an account also returns a token
+    pub fn with_tokens(mut self, tokens: Tokens) -> Self {
+        let tokens = Arc::new(tokens);
+        self.methods.push(tokens.clone());
+        self.tokens = Some(tokens);
+        self
+    }
+
+    /// The layout of an auth folder, every part is optional:
+    /// - `password`: the server password
+    /// - `accounts`: the account file
+    /// - `token_key`: the key tokens are signed with, created when there are accounts
+    pub fn from_folder<P: AsRef<Path>>(folder: P) -> io::Result<Self> {
+        let folder = folder.as_ref();
+        let mut auth = Self::default();
+        match fs::read_to_string(folder.join("password")) {
+            Ok(password) => auth = auth.with(ServerPassword(password.trim_end().to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        match Accounts::load(folder.join("accounts")) {
+            Ok(accounts) => {
+                let accounts = Arc::new(accounts);
+                let tokens = Tokens::open(folder.join("token_key"), accounts.clone())?;
+                auth = auth.with_accounts(accounts).with_tokens(tokens)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        Ok(auth)
+    }
+
+    pub fn required(&self) -> bool {
+        !self.methods.is_empty()
+    }
+
+    pub fn reserved(&self) -> &HashSet<String> {
+        &self.reserved
+    }
+
+    /// The identity and, for accounts, a fresh token
+    pub fn authenticate(&self, credentials: &Credentials) -> Option<(Identity, Option<String>)> {
+        if let (Credentials::Anonymous, false) = (credentials, self.required()) {
+            return Some((Identity::default(), None));
+        }
+        let identity = self
+            .methods
+            .iter()
+            .find_map(|method| method.authenticate(credentials))?;
+        let token = self.tokens.as_ref().and_then(|t| t.issue(&identity));
+        Some((identity, token))
+    }
+}
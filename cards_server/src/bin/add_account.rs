@@ -0,0 +1,48 @@
+//! Adds an account to an account file, or replaces it, reading the password from stdin
+//!
+//! Usage: add_account <file> <name> [admin]
+
+use cards_server::auth::account_line;
+
+use std::{
+    fs,
+    io::{self, BufRead},
+};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let (path, name) = match (args.next(), args.next()) {
+        (Some(path), Some(name)) => (path, name),
+        _ => {
+            eprintln!("Usage: add_account <file> <name> [admin]");
+            std::process::exit(1);
+        }
+    };
+    let admin = args.next().as_deref() == Some("admin");
+    if name.is_empty() || name.chars().any(char::is_control) {
+        eprintln!("Invalid name");
+        std::process::exit(1);
+    }
+
+    eprintln!("Password for {}:", name);
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password).unwrap();
+    let password = password.trim_end_matches(&['\r', '\n'][..]);
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => panic!("Unable to read {}: {}", path, e),
+    };
+    let prefix = format!("{}:", name);
+    let mut lines: Vec<String> = contents
+        .lines()
+        .filter(|line| {
+            // Only the role, salt and hash follow the name
+            !(line.starts_with(&prefix) && line[prefix.len()..].matches(':').count() == 2)
+        })
+        .map(String::from)
+        .collect();
+    lines.push(account_line(&name, password, admin));
+    fs::write(&path, lines.join("\n") + "\n").unwrap();
+}
@@ -0,0 +1,176 @@
+//! Plays matches of a game with bots, or random legal actions for games without a bot, and reports how they went
+//!
+//! Usage: simulate <game> [matches] [players]
+
+use cards_server::{
+    bot::Bot,
+    game::{Instance, ThreadSafeGame, Viewer},
+    load_games,
+};
+
+use rand::{seq::SliceRandom, thread_rng, Rng};
+use std::time::{Duration, Instant};
+
+/// Matches that take longer than this are given up as unfinished
+const MAX_ACTIONS: usize = 10_000;
+const USAGE: &str = "Usage: simulate <game> [matches] [players]";
+
+#[derive(Default)]
+struct Report {
+    wins: Vec<usize>,
+    unfinished: usize,
+    actions: usize,
+    /// Matches in which lua failed at least once
+    lua_errors: usize,
+    elapsed: Duration,
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let name = match args.next() {
+        Some(name) => name,
+        None => fail(USAGE),
+    };
+    let game = match load_games("games").into_iter().find(|g| g.name() == &name) {
+        Some(game) => game.thread_safe(),
+        None => fail(&format!("No game named {}", name)),
+    };
+    let mut number = |default| match args.next() {
+        Some(x) => x.parse().unwrap_or_else(|_| fail(USAGE)),
+        None => default,
+    };
+    let matches: usize = number(100);
+    if matches == 0 {
+        fail(USAGE);
+    }
+    let (min, max) = game.manifest().players;
+    let players: usize = number(min);
+    if players < min || players > max {
+        fail(&format!(
+            "{} is played by {} to {} players, not {}",
+            game, min, max, players
+        ));
+    }
+
+    let mut report = Report {
+        wins: vec![0; players],
+        ..Default::default()
+    };
+    let start = Instant::now();
+    for _ in 0..matches {
+        play(&game, players, &mut report);
+    }
+    report.elapsed = start.elapsed();
+
+    println!("{} - {} matches of {} players", game, matches, players);
+    for (seat, wins) in report.wins.iter().enumerate() {
+        println!(
+            "  seat {}: {} wins ({:.1}%)",
+            seat + 1,
+            wins,
+            *wins as f64 * 100. / matches as f64
+        );
+    }
+    println!("  unfinished: {}", report.unfinished);
+    println!(
+        "  average length: {:.1} actions",
+        report.actions as f64 / matches as f64
+    );
+    println!("  matches with lua errors: {}", report.lua_errors);
+    println!(
+        "  {:.0} actions per second",
+        report.actions as f64 / report.elapsed.as_secs_f64()
+    );
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1)
+}
+
+fn play(game: &ThreadSafeGame, players: usize, report: &mut Report) {
+    let mut lua_error = false;
+    match simulate(game, players, report, &mut lua_error) {
+        Some(winner) => report.wins[winner] += 1,
+        None => report.unfinished += 1,
+    }
+    if lua_error {
+        report.lua_errors += 1;
+    }
+}
+
+/// Plays a match, returns its winner if it ends.
+/// Lua errors only set the flag, a match counts once however many it hits.
+fn simulate(
+    game: &ThreadSafeGame,
+    players: usize,
+    report: &mut Report,
+    lua_error: &mut bool,
+) -> Option<usize> {
+    let mut instance = game.instance();
+    let options = game
+        .manifest()
+        .resolve_options(&Default::default())
+        .unwrap();
+    if let Err(e) = instance.setup(players, &options) {
+        eprintln!("setup failed: {}", e);
+        *lua_error = true;
+        return None;
+    }
+    let bots: Vec<Option<Bot>> = (0..players)
+        .map(|_| {
+            game.bot()
+                .map(|bot| bot.unwrap_or_else(|e| fail(&format!("Unable to load the bot: {}", e))))
+        })
+        .collect();
+    for _ in 0..MAX_ACTIONS {
+        match instance.outcome() {
+            Ok(Some(outcome)) => return outcome.standings.first().copied(),
+            Ok(None) => (),
+            Err(_) => *lua_error = true,
+        }
+        let seat = match (instance.prompted(), instance.turn()) {
+            (Ok(Some(seat)), _) | (_, Ok(Some(seat))) => seat,
+            _ => thread_rng().gen_range(0, players),
+        };
+        let acted = act(&mut instance, bots[seat].as_ref(), seat).unwrap_or_else(|_| {
+            *lua_error = true;
+            false
+        });
+        if acted {
+            report.actions += 1;
+        }
+        // Same as a player running out of time in the server
+        if !acted && instance.timeout(seat, game.manifest().draw_pile).is_err() {
+            *lua_error = true;
+        }
+    }
+    None
+}
+
+/// Plays one action for the seat, with its bot if it has one or at random if not.
+/// Returns whether an action was chosen.
+fn act(instance: &mut Instance, bot: Option<&Bot>, seat: usize) -> rlua::Result<bool> {
+    let view = instance.view(Viewer::Seat(seat))?;
+    let mut rng = thread_rng();
+    if let Some(prompt) = &view.prompt {
+        let option = match bot {
+            Some(bot) => bot.answer_prompt(&view, prompt)?,
+            None => rng.gen_range(0, prompt.options.len().max(1)),
+        };
+        return instance.answer(seat, option).map(|_| true);
+    }
+    let legal = instance.legal_actions(seat)?;
+    let pile = match bot {
+        Some(bot) => bot.choose_action(&view, &legal)?,
+        None => legal.choose(&mut rng).copied(),
+    };
+    match pile {
+        Some(pile) if legal.contains(&pile) => instance.click(seat, pile).map(|_| true),
+        Some(pile) => Err(rlua::Error::RuntimeError(format!(
+            "{:?} is not a legal action",
+            pile
+        ))),
+        None => Ok(false),
+    }
+}
@@ -0,0 +1,98 @@
+use crate::game::{new_lua, pile_id};
+
+use cards_protocol::{Card, GameView, PileId, PileView, Prompt};
+use rlua::{Context, Lua, Table};
+
+/// A seat played by the game's `bot.lua`, which only ever sees the view of its own seat
+pub struct Bot {
+    lua: Lua,
+}
+
+impl Bot {
+    pub fn new(source: &str) -> rlua::Result<Self> {
+        let lua = new_lua();
+        lua.context(|ctx| ctx.load(source).exec())?;
+        Ok(Self { lua })
+    }
+
+    /// Calls `choose_action(view)`, which returns `{common = i}`, `{own = i}` or nil to do nothing.
+    /// The legal actions are in `view.legal_actions`.
+    pub fn choose_action(&self, view: &GameView, legal: &[PileId]) -> rlua::Result<Option<PileId>> {
+        self.lua.context(|ctx| {
+            let choose_action: rlua::Function = ctx.globals().get("choose_action")?;
+            let view = view_table(ctx, view)?;
+            let legal_actions = ctx.create_table()?;
+            for (i, action) in legal.iter().enumerate() {
+                legal_actions.set(i + 1, action_table(ctx, *action)?)?;
+            }
+            view.set("legal_actions", legal_actions)?;
+            let action: Option<Table> = choose_action.call(view)?;
+            action.map(pile_id).transpose()
+        })
+    }
+
+    /// Calls `answer_prompt(view, prompt)`, which returns the index of the chosen option
+    pub fn answer_prompt(&self, view: &GameView, prompt: &Prompt) -> rlua::Result<usize> {
+        self.lua.context(|ctx| {
+            let answer_prompt: rlua::Function = ctx.globals().get("answer_prompt")?;
+            let prompt_table = ctx.create_table()?;
+            prompt_table.set("question", prompt.question.as_str())?;
+            prompt_table.set("options", prompt.options.clone())?;
+            let option: usize = answer_prompt.call((view_table(ctx, view)?, prompt_table))?;
+            Ok(option.saturating_sub(1))
+        })
+    }
+}
+
+/// Builds the lua version of a view, seats and piles are 1-based and hidden cards are `false`
+fn view_table<'lua>(ctx: Context<'lua>, view: &GameView) -> rlua::Result<Table<'lua>> {
+    let piles = |piles: &[PileView]| -> rlua::Result<Table<'lua>> {
+        let table = ctx.create_table()?;
+        for (i, pile) in piles.iter().enumerate() {
+            table.set(i + 1, pile_table(ctx, pile)?)?;
+        }
+        Ok(table)
+    };
+    let table = ctx.create_table()?;
+    table.set("seat", view.seat.map(|x| x + 1))?;
+    table.set("turn", view.turn.map(|x| x + 1))?;
+    table.set("piles", piles(&view.piles)?)?;
+    let player_piles = ctx.create_table()?;
+    for (i, seat) in view.player_piles.iter().enumerate() {
+        player_piles.set(i + 1, piles(seat)?)?;
+    }
+    table.set("player_piles", player_piles)?;
+    Ok(table)
+}
+
+fn action_table(ctx: Context, action: PileId) -> rlua::Result<Table> {
+    let table = ctx.create_table()?;
+    match action {
+        PileId::Common(i) => table.set("common", i + 1)?,
+        PileId::Own(i) => table.set("own", i + 1)?,
+    }
+    Ok(table)
+}
+
+fn pile_table<'lua>(ctx: Context<'lua>, pile: &PileView) -> rlua::Result<Table<'lua>> {
+    let table = ctx.create_table()?;
+    table.set("face_down", pile.face_down)?;
+    let cards = ctx.create_table()?;
+    for (i, card) in pile.cards.iter().enumerate() {
+        match card {
+            Some(card) => cards.set(i + 1, card_table(ctx, card)?)?,
+            None => cards.set(i + 1, false)?,
+        }
+    }
+    table.set("cards", cards)?;
+    Ok(table)
+}
+
+fn card_table<'lua>(ctx: Context<'lua>, card: &Card) -> rlua::Result<Table<'lua>> {
+    let table = ctx.create_table()?;
+    table.set("image", card.image.as_str())?;
+    for (k, v) in &card.properties {
+        table.set(k.as_str(), v.as_str())?;
+    }
+    Ok(table)
+}
@@ -0,0 +1,177 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Longest message accepted, in characters
+pub const MAX_MESSAGE_LENGTH: usize = 500;
+/// Messages a connection can send in a burst
+const BURST: u32 = 5;
+/// Time it takes to be able to send one more message after a burst
+const REFILL: Duration = Duration::from_secs(2);
+
+/// Token bucket limiting how fast a connection can do something, by default chat
+pub struct RateLimiter {
+    tokens: u32,
+    last_refill: Instant,
+    burst: u32,
+    refill: Duration,
+}
+
+impl RateLimiter {
+    /// Allows a burst, then one more each time the refill passes
+    pub fn new(burst: u32, refill: Duration) -> Self {
+        Self {
+            tokens: burst,
+            last_refill: Instant::now(),
+            burst,
+            refill,
+        }
+    }
+
+    /// Returns whether it can be done now, and counts it if so
+    pub fn allow(&mut self) -> bool {
+        self.allow_at(Instant::now())
+    }
+
+    /// Whether it can be done now, without counting it
+    pub fn ready(&mut self) -> bool {
+        self.refill_at(Instant::now());
+        self.tokens > 0
+    }
+
+    /// Whether it's back to a whole burst, at which point it can be forgotten
+    fn full(&mut self) -> bool {
+        self.refill_at(Instant::now());
+        self.tokens == self.burst
+    }
+
+    fn refill_at(&mut self, now: Instant) {
+        let refilled = ((now - self.last_refill).as_millis() / self.refill.as_millis()) as u32;
+        if refilled > 0 {
+            self.tokens = (self.tokens + refilled).min(self.burst);
+            // The time towards the next token isn't lost
+            self.last_refill += self.refill * refilled;
+        }
+    }
+
+    fn allow_at(&mut self, now: Instant) -> bool {
+        self.refill_at(now);
+        if self.tokens > 0 {
+            self.tokens -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(BURST, REFILL)
+    }
+}
+
+/// A rate limiter for each address, shared by its connections so that reconnecting doesn't start over
+#[derive(Clone)]
+pub struct AddressLimiter {
+    limiters: Arc<Mutex<HashMap<IpAddr, RateLimiter>>>,
+    burst: u32,
+    refill: Duration,
+}
+
+impl AddressLimiter {
+    pub fn new(burst: u32, refill: Duration) -> Self {
+        Self {
+            limiters: Default::default(),
+            burst,
+            refill,
+        }
+    }
+
+    /// Whether the address can do it now, without counting it
+    pub fn ready(&self, addr: IpAddr) -> bool {
+        self.limiters
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .is_none_or(|limiter| limiter.ready())
+    }
+
+    /// Returns whether the address can do it now, and counts it if so
+    pub fn allow(&self, addr: IpAddr) -> bool {
+        let mut limiters = self.limiters.lock().unwrap();
+        // The addresses that stopped don't have to be remembered
+        limiters.retain(|_, limiter| !limiter.full());
+        limiters
+            .entry(addr)
+            .or_insert_with(|| RateLimiter::new(self.burst, self.refill))
+            .allow()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(start: Instant) -> RateLimiter {
+        RateLimiter {
+            last_refill: start,
+            ..RateLimiter::default()
+        }
+    }
+
+    #[test]
+    fn a_burst_empties_the_bucket() {
+        let start = Instant::now();
+        let mut limiter = limiter(start);
+        for _ in 0..BURST {
+            assert!(limiter.allow_at(start));
+        }
+        assert!(!limiter.allow_at(start));
+        assert!(!limiter.allow_at(start + REFILL / 2));
+        assert!(limiter.allow_at(start + REFILL));
+        assert!(!limiter.allow_at(start + REFILL));
+    }
+
+    #[test]
+    fn part_of_a_refill_carries_over() {
+        let start = Instant::now();
+        let mut limiter = limiter(start);
+        for _ in 0..BURST {
+            assert!(limiter.allow_at(start));
+        }
+        let later = start + REFILL * 5 / 2;
+        assert!(limiter.allow_at(later));
+        assert!(limiter.allow_at(later));
+        assert!(!limiter.allow_at(later));
+        // Half a refill was left from before
+        assert!(limiter.allow_at(start + REFILL * 3));
+    }
+
+    #[test]
+    fn the_bucket_holds_a_burst_at_most() {
+        let start = Instant::now();
+        let mut limiter = limiter(start);
+        let later = start + REFILL * 100;
+        for _ in 0..BURST {
+            assert!(limiter.allow_at(later));
+        }
+        assert!(!limiter.allow_at(later));
+    }
+
+    #[test]
+    fn each_address_has_its_own_bucket() {
+        let limiter = AddressLimiter::new(2, Duration::from_secs(60));
+        let (a, b) = ([127, 0, 0, 1].into(), [127, 0, 0, 2].into());
+        assert!(limiter.allow(a));
+        assert!(limiter.allow(a));
+        assert!(!limiter.ready(a));
+        // A clone is what another connection of the address gets
+        assert!(!limiter.clone().allow(a));
+        assert!(limiter.ready(b));
+        assert!(limiter.allow(b));
+    }
+}
@@ -0,0 +1,365 @@
+use cards_protocol::{LogLevel, Request, Timeouts};
+use cards_subscriber::{ApplyTo, Filter, TargetKind};
+use serde::{
+    de::{value::StrDeserializer, IntoDeserializer},
+    Deserialize,
+};
+
+use std::{fs, net::SocketAddr, path::PathBuf, time::Duration};
+
+/// The file read when no other is given, it's fine for it not to exist
+pub const DEFAULT_CONFIG: &str = "server.toml";
+
+pub const USAGE: &str = "Usage: cards_server [options]
+
+Options override the config file:
+    --config <file>             Config file, server.toml by default
+    --address <ip:port>         Address to listen on
+    --games <folder>            Folder the games are loaded from
+    --logs <folder>             Folder the logs are written to
+    --log-filter <filter>       Shows the messages of a target up to a level, as target=level[@output].
+                                The level may be off, the output stdout, main, target or all
+    --max-rooms <count>         Rooms open at the same time
+    --max-connections <count>   Connections open at the same time
+    --enable <feature>          One of matchmaking, tournaments, chat, spectators or bots
+    --disable <feature>
+    --tls-cert <file>           Turns on TLS, needs --tls-key
+    --tls-key <file>
+    --metrics <ip:port>         Serves the metrics over HTTP, meant for a local address
+    --help";
+
+/// How the server is set up, from a TOML file and the command line
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub address: SocketAddr,
+    pub games: PathBuf,
+    pub logs: PathBuf,
+    /// Targets with a log file of their own
+    pub log_targets: Vec<String>,
+    /// Tried in order, the first one for a target decides
+    pub log_filters: Vec<LogFilter>,
+    pub stats: PathBuf,
+    pub tournaments: PathBuf,
+    /// The folder with the server password, accounts and token key
+    pub auth: PathBuf,
+    /// Where the games in progress are written when the server shuts down
+    pub snapshots: PathBuf,
+    /// Seconds the games in progress get to finish when shutting down
+    pub shutdown_grace: u64,
+    /// TLS is off without it
+    pub tls: Option<TlsConfig>,
+    /// Where the Prometheus metrics are served over HTTP, off without it.
+    /// Nothing is checked, so it is best kept local
+    pub metrics: Option<SocketAddr>,
+    pub heartbeat: Heartbeat,
+    pub limits: Limits,
+    pub features: Features,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            address: ([0, 0, 0, 0], 25566).into(),
+            games: "games".into(),
+            logs: "logs/server".into(),
+            log_targets: vec!["cards_server::server".into()],
+            log_filters: Vec::new(),
+            stats: "stats".into(),
+            tournaments: "tournaments".into(),
+            auth: "auth".into(),
+            snapshots: "snapshots".into(),
+            shutdown_grace: 30,
+            tls: None,
+            metrics: None,
+            heartbeat: Heartbeat::default(),
+            limits: Limits::default(),
+            features: Features::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate followed by any intermediates
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// In seconds
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct Heartbeat {
+    /// How often connections are pinged
+    pub interval: u64,
+    /// Connections that send nothing for this long, not even a pong, are dropped
+    pub idle_timeout: u64,
+    /// How long the rest of a frame can take once it starts
+    pub frame_timeout: u64,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        let timeouts = Timeouts::default();
+        Self {
+            interval: timeouts.ping_interval.as_secs(),
+            idle_timeout: timeouts.idle.as_secs(),
+            frame_timeout: timeouts.frame.as_secs(),
+        }
+    }
+}
+
+impl Heartbeat {
+    /// Connections would be dropped before they are pinged otherwise
+    fn check(&self) -> Result<(), String> {
+        if self.idle_timeout <= self.interval {
+            return Err(format!(
+                "The idle timeout ({}s) has to be longer than the ping interval ({}s)",
+                self.idle_timeout, self.interval
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            ping_interval: Duration::from_secs(self.interval.max(1)),
+            idle: Duration::from_secs(self.idle_timeout.max(1)),
+            frame: Duration::from_secs(self.frame_timeout.max(1)),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Unlimited when missing
+    pub max_rooms: Option<usize>,
+    pub max_connections: Option<usize>,
+    /// The largest request in bytes, connections that send a larger one are dropped
+    pub max_frame: usize,
+    /// The bytes waiting to be sent to a connection, it's dropped if it stays over them for the grace in seconds
+    pub max_queue: usize,
+    pub queue_grace: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_rooms: None,
+            max_connections: None,
+            max_frame: 64 * 1024,
+            max_queue: cards_protocol::DEFAULT_MAX_QUEUE,
+            queue_grace: cards_protocol::DEFAULT_QUEUE_GRACE.as_secs(),
+        }
+    }
+}
+
+/// Parts of the server that can be turned off
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    pub matchmaking: bool,
+    pub tournaments: bool,
+    pub chat: bool,
+    pub spectators: bool,
+    pub bots: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            matchmaking: true,
+            tournaments: true,
+            chat: true,
+            spectators: true,
+            bots: true,
+        }
+    }
+}
+
+impl Features {
+    fn toggle(&mut self, name: &str, on: bool) -> Result<(), String> {
+        let feature = match name {
+            "matchmaking" => &mut self.matchmaking,
+            "tournaments" => &mut self.tournaments,
+            "chat" => &mut self.chat,
+            "spectators" => &mut self.spectators,
+            "bots" => &mut self.bots,
+            _ => return Err(format!("Unknown feature {}", name)),
+        };
+        *feature = on;
+        Ok(())
+    }
+
+    /// Whether the request belongs to a feature that is on
+    pub fn allows(&self, req: &Request) -> bool {
+        match req {
+            Request::Enqueue { .. } | Request::Dequeue => self.matchmaking,
+            Request::CreateTournament { .. }
+            | Request::JoinTournament { .. }
+            | Request::StartTournament { .. }
+            | Request::Tournament { .. } => self.tournaments,
+            Request::Mute { .. } => self.chat,
+            Request::Spectate { .. } => self.spectators,
+            Request::AddBot { .. } => self.bots,
+            // Bots would fill the seats of the players that leave
+            Request::CreateRoom { settings, .. } if settings.bots_take_over => self.bots,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    Stdout,
+    Main,
+    Target,
+    All,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LogFilter {
+    pub target: String,
+    pub level: LogLevel,
+    #[serde(default = "all_outputs")]
+    pub output: LogOutput,
+}
+
+fn all_outputs() -> LogOutput {
+    LogOutput::All
+}
+
+fn word(x: &str) -> StrDeserializer<'_, serde::de::value::Error> {
+    x.into_deserializer()
+}
+
+impl LogFilter {
+    /// Parses `target=level[@output]`
+    fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid log filter {}, expected target=level[@output]", s);
+        let (target, rest) = s.split_once('=').ok_or_else(invalid)?;
+        let (level, output) = match rest.split_once('@') {
+            Some((level, output)) => (level, Some(output)),
+            None => (rest, None),
+        };
+        Ok(Self {
+            target: target.to_string(),
+            level: LogLevel::deserialize(word(level)).map_err(|_| invalid())?,
+            output: match output {
+                Some(output) => LogOutput::deserialize(word(output)).map_err(|_| invalid())?,
+                None => LogOutput::All,
+            },
+        })
+    }
+
+    pub fn filter(&self) -> Filter {
+        let level = tracing_level(self.level);
+        let apply_to = match self.output {
+            LogOutput::Stdout => ApplyTo::Stdout,
+            LogOutput::Main => ApplyTo::MainLog,
+            LogOutput::Target => ApplyTo::SpecificLog,
+            LogOutput::All => ApplyTo::All,
+        };
+        Filter::new(
+            level,
+            Some(TargetKind::Target(self.target.clone())),
+            apply_to,
+        )
+    }
+}
+
+/// `None` for off
+pub fn tracing_level(level: LogLevel) -> Option<tracing::Level> {
+    use tracing::Level;
+    match level {
+        LogLevel::Off => None,
+        LogLevel::Error => Some(Level::ERROR),
+        LogLevel::Warn => Some(Level::WARN),
+        LogLevel::Info => Some(Level::INFO),
+        LogLevel::Debug => Some(Level::DEBUG),
+        LogLevel::Trace => Some(Level::TRACE),
+    }
+}
+
+impl Config {
+    /// Reads the config file and applies the flags on top, the errors are meant for the user
+    pub fn from_args<I: Iterator<Item = String>>(args: I) -> Result<Self, String> {
+        let args: Vec<String> = args.collect();
+        let mut config = match flag_value(&args, "--config")? {
+            Some(path) => Self::load(&path)?,
+            None if std::path::Path::new(DEFAULT_CONFIG).exists() => Self::load(DEFAULT_CONFIG)?,
+            None => Self::default(),
+        };
+        let mut args = args.into_iter();
+        let mut tls_cert = None;
+        let mut tls_key = None;
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("Missing the value of {}\n\n{}", flag, USAGE))?;
+            let number = |x: &str| {
+                x.parse::<usize>()
+                    .map_err(|_| format!("{} needs a number, not {}", flag, x))
+            };
+            match flag.as_str() {
+                "--config" => (),
+                "--address" => {
+                    config.address = value
+                        .parse()
+                        .map_err(|_| format!("Invalid address {}", value))?
+                }
+                "--games" => config.games = value.into(),
+                "--logs" => config.logs = value.into(),
+                "--log-filter" => config.log_filters.push(LogFilter::parse(&value)?),
+                "--max-rooms" => config.limits.max_rooms = Some(number(&value)?),
+                "--max-connections" => config.limits.max_connections = Some(number(&value)?),
+                "--enable" => config.features.toggle(&value, true)?,
+                "--disable" => config.features.toggle(&value, false)?,
+                "--tls-cert" => tls_cert = Some(value.into()),
+                "--tls-key" => tls_key = Some(value.into()),
+                "--metrics" => {
+                    config.metrics = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("Invalid address {}", value))?,
+                    )
+                }
+                _ => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+            }
+        }
+        match (tls_cert, tls_key) {
+            (Some(cert), Some(key)) => config.tls = Some(TlsConfig { cert, key }),
+            (None, None) => (),
+            _ => return Err("--tls-cert and --tls-key go together".to_string()),
+        }
+        Ok(config)
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        let config: Self =
+            toml::from_str(&contents).map_err(|e| format!("Invalid config {}: {}", path, e))?;
+        config
+            .heartbeat
+            .check()
+            .map_err(|e| format!("Invalid config {}: {}", path, e))?;
+        Ok(config)
+    }
+}
+
+/// The value of the last use of a flag
+fn flag_value(args: &[String], flag: &str) -> Result<Option<String>, String> {
+    match args.iter().rposition(|x| x == flag) {
+        Some(i) => args
+            .get(i + 1)
+            .cloned()
+            .map(Some)
+            .ok_or_else(|| format!("Missing the value of {}", flag)),
+        None => Ok(None),
+    }
+}
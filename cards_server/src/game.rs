@@ -1,56 +1,200 @@
-use rlua::Lua;
-use std::fs::read_to_string;
+use crate::bot::Bot;
 
-const UTILS: &'static str = include_str!("utils.lua");
+use cards_protocol::{
+    Card, GameOption, GameView, OptionKind, OptionValue, PileId, PileView, Prompt,
+};
+use rlua::{FromLua, Lua, RegistryKey, Table};
+use std::{collections::BTreeMap, fs::read_to_string, sync::Arc};
+
+const UTILS: &str = include_str!("utils.lua");
+/// Registry key under which a running instance keeps its piles
+const STATE: &str = "cards_state";
+/// Registry key set by `irreversible()` during an action
+const IRREVERSIBLE: &str = "cards_irreversible";
 
 pub struct Game {
     name: String,
     version: String,
-    lua: Lua,
+    manifest: Manifest,
+    source: Arc<String>,
+    bot: Option<Arc<String>>,
+}
+
+/// The optional settings a game declares as globals next to its name and version
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    /// Minimum and maximum amount of players
+    pub players: (usize, usize),
+    /// Whether the game allows spectators at all
+    pub spectators: bool,
+    /// Seconds a seat has to play its turn
+    pub turn_time: Option<u64>,
+    /// Seconds a seat has to answer a prompt
+    pub prompt_time: Option<u64>,
+    /// Pile clicked on behalf of a seat that ran out of time, before passing the turn
+    pub draw_pile: Option<usize>,
+    /// What each room can choose, sorted by name
+    pub options: Vec<GameOption>,
+}
+
+impl Manifest {
+    fn read(globals: &Table) -> rlua::Result<Self> {
+        let range: Table = globals.get("players")?;
+        Ok(Self {
+            players: (range.get(1)?, range.get(2)?),
+            spectators: globals.get::<_, Option<bool>>("spectators")?.unwrap_or(true),
+            turn_time: globals.get("turn_time")?,
+            prompt_time: globals.get("prompt_time")?,
+            // Numbered from 1 like the piles in lua
+            draw_pile: globals
+                .get::<_, Option<usize>>("draw_pile")?
+                .map(|x| lua_index(x, "draw_pile"))
+                .transpose()?,
+            options: read_options(globals)?,
+        })
+    }
+
+    /// The value of every option, taken from the chosen ones or the defaults.
+    /// Fails with the name of an unknown or invalid option.
+    pub fn resolve_options(
+        &self,
+        chosen: &BTreeMap<String, OptionValue>,
+    ) -> Result<BTreeMap<String, OptionValue>, String> {
+        if let Some(name) = chosen
+            .keys()
+            .find(|name| self.options.iter().all(|x| &x.name != *name))
+        {
+            return Err(name.clone());
+        }
+        self.options
+            .iter()
+            .map(|option| match chosen.get(&option.name) {
+                Some(value) if option.accepts(value) => Ok((option.name.clone(), value.clone())),
+                Some(_) => Err(option.name.clone()),
+                None => Ok((option.name.clone(), option.default.clone())),
+            })
+            .collect()
+    }
+}
+
+/// Reads the `options` global, which maps each name to
+/// `{kind = "bool" | "enum" | "range", description = ..., default = ...}`.
+/// Enums list their `values`, ranges have a `min` and a `max`.
+fn read_options(globals: &Table) -> rlua::Result<Vec<GameOption>> {
+    let table = match globals.get::<_, Option<Table>>("options")? {
+        Some(table) => table,
+        None => return Ok(Vec::new()),
+    };
+    let mut options = Vec::new();
+    for pair in table.pairs::<String, Table>() {
+        let (name, spec) = pair?;
+        let invalid =
+            |problem: &str| rlua::Error::RuntimeError(format!("Option {} {}", name, problem));
+        let (kind, default) = match spec.get::<_, String>("kind")?.as_str() {
+            "bool" => (
+                OptionKind::Bool,
+                OptionValue::Bool(spec.get::<_, Option<bool>>("default")?.unwrap_or(false)),
+            ),
+            "enum" => {
+                let values: Vec<String> = spec.get("values")?;
+                let default = match spec.get::<_, Option<String>>("default")? {
+                    Some(default) => default,
+                    None => values
+                        .first()
+                        .cloned()
+                        .ok_or_else(|| invalid("has no values"))?,
+                };
+                (OptionKind::Enum(values), OptionValue::Text(default))
+            }
+            "range" => {
+                let (min, max): (i64, i64) = (spec.get("min")?, spec.get("max")?);
+                let default = spec.get::<_, Option<i64>>("default")?.unwrap_or(min);
+                (OptionKind::Range { min, max }, OptionValue::Number(default))
+            }
+            kind => return Err(invalid(&format!("has an unknown kind: {}", kind))),
+        };
+        let option = GameOption {
+            name: name.clone(),
+            description: spec.get("description")?,
+            kind,
+            default,
+        };
+        if !option.accepts(&option.default) {
+            return Err(invalid("has an invalid default"));
+        }
+        options.push(option);
+    }
+    options.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(options)
+}
+
+/// Creates a lua state with the utils loaded
+pub fn new_lua() -> Lua {
+    let lua = rlua::Lua::new();
+    lua.context(|ctx| {
+        ctx.load(UTILS)
+            .exec()
+            .unwrap();
+        let shuffle = ctx
+            .create_function(|ctx, table: rlua::Table| {
+                use rand::seq::SliceRandom;
+                use rand::thread_rng;
+                let mut rng = thread_rng();
+                let res = ctx.create_table()?;
+                let mut seq: Vec<rlua::Value> =
+                    table.sequence_values().map(|x| x.unwrap()).collect();
+                // println!("Unshuffled: [{}]", seq.iter().enumerate().map(|(i, x)| format!("{}: {:?}", i, x)).fold("".into(), |f, x| format!("{}, {}", f, x)));
+                seq.shuffle(&mut rng);
+                // println!("Unshuffled: [{}]", seq.iter().enumerate().map(|(i, x)| format!("{}: {:?}", i, x)).fold("".into(), |f, x| format!("{}, {}", f, x)));
+                for (i, x) in seq.into_iter().enumerate() {
+                    res.set(i + 1, x)?;
+                }
+                Ok(res)
+            })
+            .unwrap();
+        ctx.globals().set("shuffle", shuffle).unwrap();
+        let game_state = ctx
+            .create_function(|ctx, ()| ctx.named_registry_value::<_, Table>(STATE))
+            .unwrap();
+        ctx.globals().set("game_state", game_state).unwrap();
+        // Marks the running action as one that can't be undone, like revealing a hidden card
+        let irreversible = ctx
+            .create_function(|ctx, ()| ctx.set_named_registry_value(IRREVERSIBLE, true))
+            .unwrap();
+        ctx.globals().set("irreversible", irreversible).unwrap();
+    }); // Load utils
+    lua
 }
 
 impl Game {
     pub fn load<P: AsRef<std::path::Path>>(file: P) -> Self {
+        Self::try_load(file).unwrap_or_else(|e| {
+            println!("[LUA] {}", e);
+            panic!()
+        })
+    }
+
+    /// Like `load`, for when a broken game shouldn't bring the server down
+    pub fn try_load<P: AsRef<std::path::Path>>(file: P) -> Result<Self, String> {
 		let mut name = String::new();
 		let mut version = String::new();
-        let lua = rlua::Lua::new();
-        lua.context(|ctx| {
-            ctx.load(UTILS)
-                .exec()
-                .unwrap();
-            let shuffle = ctx
-                .create_function(|ctx, table: rlua::Table| {
-                    use rand::seq::SliceRandom;
-                    use rand::thread_rng;
-                    let mut rng = thread_rng();
-                    let res = ctx.create_table()?;
-                    let mut seq: Vec<rlua::Value> =
-                        table.sequence_values().map(|x| x.unwrap()).collect();
-                    // println!("Unshuffled: [{}]", seq.iter().enumerate().map(|(i, x)| format!("{}: {:?}", i, x)).fold("".into(), |f, x| format!("{}, {}", f, x)));
-                    seq.shuffle(&mut rng);
-                    // println!("Unshuffled: [{}]", seq.iter().enumerate().map(|(i, x)| format!("{}: {:?}", i, x)).fold("".into(), |f, x| format!("{}, {}", f, x)));
-                    for (i, x) in seq.into_iter().enumerate() {
-                        res.set(i + 1, x)?;
-                    }
-                    Ok(res)
-                })
-                .unwrap();
-            ctx.globals().set("shuffle", shuffle).unwrap();
-        }); // Load utils
-        lua.context(|ctx| {
+        let mut manifest = None;
+        // An optional bot.lua next to game.lua can fill seats
+        let bot = read_to_string(file.as_ref().with_file_name("bot.lua"))
+            .ok()
+            .map(Arc::new);
+        let source = Arc::new(
+            read_to_string(&file).map_err(|e| format!("{}: {}", file.as_ref().display(), e))?,
+        );
+        let lua = new_lua();
+        lua.context(|ctx| -> rlua::Result<()> {
             // println!("FOUND game.lua in {}", folder.path().display());
-            ctx.load(&read_to_string(file).unwrap())
-                .exec()
-                .map_err(|e| {
-                    println!("[LUA] {}", e);
-                    panic!()
-                })
-                .unwrap();
+            ctx.load(source.as_str()).exec()?;
             let globals = ctx.globals().clone();
-            name = globals.get("name").unwrap();
-            version = globals.get("version").unwrap();
-			
-			
+            name = globals.get("name")?;
+            version = globals.get("version")?;
+            manifest = Some(Manifest::read(&globals)?);
+
             // let (piles, player_piles): (rlua::Table, rlua::Table) = setup
             //     .call(2)
             //     .map_err(|e| {
@@ -124,8 +268,16 @@ impl Game {
             //     println!("DECK [PLAYER] {}", i);
             //     print_table(" - ".into(), pile);
             // }
-		});
-		Self {name, version, lua}
+            Ok(())
+        })
+        .map_err(|e| lua_message(&e))?;
+        Ok(Self {
+            name,
+            version,
+            manifest: manifest.unwrap(),
+            source,
+            bot,
+        })
     }
     
     pub fn name(&self) -> &String {
@@ -139,7 +291,10 @@ impl Game {
     pub fn thread_safe(&self) -> ThreadSafeGame {
         ThreadSafeGame {
             name: self.name().clone(),
-            version: self.version().clone()
+            version: self.version().clone(),
+            manifest: self.manifest.clone(),
+            source: self.source.clone(),
+            bot: self.bot.clone(),
         }
     }
 }
@@ -159,7 +314,10 @@ impl std::fmt::Debug for Game {
 #[derive(Debug, Clone)]
 pub struct ThreadSafeGame {
     name: String,
-    version: String
+    version: String,
+    manifest: Manifest,
+    source: Arc<String>,
+    bot: Option<Arc<String>>,
 }
 
 impl ThreadSafeGame {    
@@ -170,6 +328,521 @@ impl ThreadSafeGame {
     pub fn version(&self) -> &String {
         &self.version
     }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Loads the game's bot.lua for one seat
+    pub fn bot(&self) -> Option<rlua::Result<Bot>> {
+        self.bot.as_ref().map(|source| Bot::new(source))
+    }
+
+    /// Creates a fresh lua state for a room to run the game in
+    pub fn instance(&self) -> Instance {
+        let lua = new_lua();
+        lua.context(|ctx| ctx.load(self.source.as_str()).exec()).unwrap();
+        Instance {
+            lua,
+            history: Vec::new(),
+        }
+    }
+}
+
+/// The loaded games, shared so that they can be reloaded while the server runs.
+/// Rooms keep playing the version they were created with.
+#[derive(Clone, Default)]
+pub struct Games {
+    games: Arc<std::sync::RwLock<Vec<ThreadSafeGame>>>,
+}
+
+impl Games {
+    pub fn new(games: Vec<ThreadSafeGame>) -> Self {
+        Self {
+            games: Arc::new(std::sync::RwLock::new(games)),
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<ThreadSafeGame> {
+        self.games
+            .read()
+            .unwrap()
+            .iter()
+            .find(|g| g.name() == name)
+            .cloned()
+    }
+
+    pub fn list(&self) -> Vec<ThreadSafeGame> {
+        self.games.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, games: Vec<ThreadSafeGame>) {
+        *self.games.write().unwrap() = games;
+    }
+}
+
+/// Who a view is being projected for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Viewer {
+    Seat(usize),
+    /// Sees only what is public
+    Spectator,
+    /// Sees every card, even face down ones
+    Omniscient,
+}
+
+/// A game being played in a room
+pub struct Instance {
+    lua: Lua,
+    /// Snapshots from before each action, oldest first
+    history: Vec<Checkpoint>,
+}
+
+/// The end of a game
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    /// Seats from first to last
+    pub standings: Vec<usize>,
+    /// The score of each seat, all 0 if the game has no `score` hook
+    pub scores: Vec<i64>,
+}
+
+/// Actions kept for undoing
+const MAX_HISTORY: usize = 100;
+
+struct Checkpoint {
+    saved: RegistryKey,
+    /// The turn the action was done in
+    turn: Option<usize>,
+}
+
+impl Instance {
+    /// Calls the game's `setup` and gives each seat its own copy of the player piles
+    /// The options are also left in the state for the handlers
+    pub fn setup(
+        &self,
+        players: usize,
+        options: &BTreeMap<String, OptionValue>,
+    ) -> rlua::Result<()> {
+        self.lua.context(|ctx| {
+            let globals = ctx.globals();
+            let setup: rlua::Function = globals.get("setup")?;
+            let deepcopy: rlua::Function = globals.get("deepcopy")?;
+            let option_table = ctx.create_table()?;
+            for (name, value) in options {
+                match value {
+                    OptionValue::Bool(x) => option_table.set(name.as_str(), *x)?,
+                    OptionValue::Text(x) => option_table.set(name.as_str(), x.as_str())?,
+                    OptionValue::Number(x) => option_table.set(name.as_str(), *x)?,
+                }
+            }
+            let (piles, player_piles): (Table, Table) =
+                setup.call((players, option_table.clone()))?;
+            let seats = ctx.create_table()?;
+            for seat in 1..=players {
+                seats.set(seat, deepcopy.call::<_, Table>(player_piles.clone())?)?;
+            }
+            let state = ctx.create_table()?;
+            state.set("piles", piles)?;
+            state.set("players", seats)?;
+            state.set("turn", 1)?;
+            state.set("options", option_table)?;
+            ctx.set_named_registry_value(STATE, state)
+        })
+    }
+
+    /// The seat whose turn it is, if the game uses turns
+    pub fn turn(&self) -> rlua::Result<Option<usize>> {
+        self.lua.context(|ctx| {
+            let state: Table = ctx.named_registry_value(STATE)?;
+            state
+                .get::<_, Option<usize>>("turn")?
+                .map(|x| lua_index(x, "turn"))
+                .transpose()
+        })
+    }
+
+    /// The seat that has to answer a prompt, if any
+    pub fn prompted(&self) -> rlua::Result<Option<usize>> {
+        self.lua.context(|ctx| {
+            let state: Table = ctx.named_registry_value(STATE)?;
+            match state.get::<_, Option<Table>>("prompt")? {
+                Some(prompt) => Ok(Some(lua_index(prompt.get("player")?, "prompt.player")?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    /// The piles the seat may click according to the game's `legal_actions(player, state)`,
+    /// every pile if the game doesn't have that hook
+    pub fn legal_actions(&self, seat: usize) -> rlua::Result<Vec<PileId>> {
+        self.lua.context(|ctx| {
+            let state: Table = ctx.named_registry_value(STATE)?;
+            match ctx.globals().get::<_, Option<rlua::Function>>("legal_actions")? {
+                Some(legal_actions) => legal_actions
+                    .call::<_, Table>((seat + 1, state))?
+                    .sequence_values()
+                    .map(|action| pile_id(action?))
+                    .collect(),
+                None => {
+                    let common = state.get::<_, Table>("piles")?.len()? as usize;
+                    let own = state
+                        .get::<_, Table>("players")?
+                        .get::<_, Table>(seat + 1)?
+                        .len()? as usize;
+                    Ok((0..common)
+                        .map(PileId::Common)
+                        .chain((0..own).map(PileId::Own))
+                        .collect())
+                }
+            }
+        })
+    }
+
+    /// How the game ended, if it did, according to the game's `check_winner(state)`.
+    /// It returns nil while the game goes on, and otherwise the winning seat, the seats in finishing order,
+    /// or true to rank every seat by `score(player, state)`.
+    /// Seats it leaves out are ranked after the ones it names, by score.
+    pub fn outcome(&self) -> rlua::Result<Option<Outcome>> {
+        self.lua.context(|ctx| {
+            let globals = ctx.globals();
+            let check_winner = match globals.get::<_, Option<rlua::Function>>("check_winner")? {
+                Some(check_winner) => check_winner,
+                None => return Ok(None),
+            };
+            let state: Table = ctx.named_registry_value(STATE)?;
+            let players = state.get::<_, Table>("players")?.len()? as usize;
+            let named: Vec<usize> = match check_winner.call(state.clone())? {
+                rlua::Value::Nil | rlua::Value::Boolean(false) => return Ok(None),
+                rlua::Value::Boolean(true) => Vec::new(),
+                rlua::Value::Table(seats) => {
+                    seats.sequence_values().collect::<rlua::Result<_>>()?
+                }
+                seat => vec![usize::from_lua(seat, ctx)?],
+            };
+            let mut standings = Vec::with_capacity(players);
+            for seat in named {
+                let index = lua_index(seat, "check_winner's seat")?;
+                if index >= players || standings.contains(&index) {
+                    return Err(rlua::Error::RuntimeError(format!(
+                        "check_winner returned an invalid seat: {}",
+                        seat
+                    )));
+                }
+                standings.push(index);
+            }
+            let scores = match globals.get::<_, Option<rlua::Function>>("score")? {
+                Some(score) => (1..=players)
+                    .map(|seat| score.call((seat, state.clone())))
+                    .collect::<rlua::Result<Vec<i64>>>()?,
+                None => vec![0; players],
+            };
+            let mut rest: Vec<usize> = (0..players).filter(|x| !standings.contains(x)).collect();
+            rest.sort_by_key(|seat| std::cmp::Reverse(scores[*seat]));
+            standings.extend(rest);
+            Ok(Some(Outcome { standings, scores }))
+        })
+    }
+
+    /// Runs a change to the game, putting the state back as it was if it fails.
+    /// A change also fails if it leaves the state in a shape that can't be shown to the players.
+    /// Returns the snapshot from before the change if it succeeds.
+    fn attempt<T>(
+        &self,
+        change: impl FnOnce() -> rlua::Result<T>,
+    ) -> rlua::Result<(T, RegistryKey)> {
+        let saved = self.lua.context(|ctx| {
+            let snapshot: rlua::Function = ctx.globals().get("snapshot_state")?;
+            let state: Table = ctx.named_registry_value(STATE)?;
+            ctx.create_registry_value(snapshot.call::<_, Table>(state)?)
+        })?;
+        match change().and_then(|x| self.view(Viewer::Omniscient).map(|_| x)) {
+            Ok(x) => Ok((x, saved)),
+            Err(e) => {
+                self.restore(saved)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn restore(&self, saved: RegistryKey) -> rlua::Result<()> {
+        self.lua.context(|ctx| {
+            let restore: rlua::Function = ctx.globals().get("restore_state")?;
+            restore.call::<_, ()>(ctx.registry_value::<Table>(&saved)?)?;
+            ctx.remove_registry_value(saved)
+        })
+    }
+
+    fn forget(&self, saved: RegistryKey) -> rlua::Result<()> {
+        self.lua.context(|ctx| ctx.remove_registry_value(saved))
+    }
+
+    /// An attempt that is kept in the history so that it can be undone
+    fn transaction<T>(&mut self, change: impl FnOnce(&Self) -> rlua::Result<T>) -> rlua::Result<T> {
+        let turn = self.turn()?;
+        // A flag left by a change that failed doesn't carry over to this one
+        self.take_irreversible()?;
+        let res = self.attempt(|| change(self));
+        let irreversible = self.take_irreversible()?;
+        let (x, saved) = res?;
+        if irreversible {
+            self.forget(saved)?;
+            for checkpoint in std::mem::take(&mut self.history) {
+                self.forget(checkpoint.saved)?;
+            }
+        } else {
+            self.history.push(Checkpoint { saved, turn });
+            if self.history.len() > MAX_HISTORY {
+                let oldest = self.history.remove(0);
+                self.forget(oldest.saved)?;
+            }
+        }
+        Ok(x)
+    }
+
+    /// Whether the running change called `irreversible()`, unsetting it
+    fn take_irreversible(&self) -> rlua::Result<bool> {
+        self.lua.context(|ctx| {
+            let irreversible = ctx.named_registry_value::<_, Option<bool>>(IRREVERSIBLE)?;
+            ctx.unset_named_registry_value(IRREVERSIBLE)?;
+            Ok(irreversible.unwrap_or(false))
+        })
+    }
+
+    /// Whether there is an action that can be undone
+    pub fn can_undo(&self) -> bool {
+        !self.history.is_empty()
+    }
+
+    /// Puts the game back to before the last action, or before all the actions done in the same turn as it
+    pub fn undo(&mut self, whole_turn: bool) -> rlua::Result<()> {
+        let last = match self.history.pop() {
+            Some(last) => last,
+            None => return Err(rlua::Error::RuntimeError("Nothing to undo".into())),
+        };
+        let mut target = last;
+        while whole_turn && self.history.last().map(|x| x.turn) == Some(target.turn) {
+            let earlier = self.history.pop().unwrap();
+            self.forget(std::mem::replace(&mut target, earlier).saved)?;
+        }
+        self.restore(target.saved)
+    }
+
+    /// Answers the pending prompt of a seat with one of its options and runs `on_answer`
+    pub fn answer(&mut self, seat: usize, option: usize) -> rlua::Result<()> {
+        self.transaction(|this| this.run_answer(seat, option))
+    }
+
+    fn run_answer(&self, seat: usize, option: usize) -> rlua::Result<()> {
+        self.lua.context(|ctx| {
+            let state: Table = ctx.named_registry_value(STATE)?;
+            let prompt = match state.get::<_, Option<Table>>("prompt")? {
+                Some(prompt) if prompt.get::<_, usize>("player")? == seat + 1 => prompt,
+                _ => return Err(rlua::Error::RuntimeError("Nothing to answer".into())),
+            };
+            if option >= prompt.get::<_, Table>("options")?.len()? as usize {
+                return Err(rlua::Error::RuntimeError("No such option".into()));
+            }
+            state.set("prompt", rlua::Value::Nil)?;
+            match ctx
+                .globals()
+                .get::<_, Option<rlua::Function>>("on_answer")?
+            {
+                Some(on_answer) => on_answer.call((seat + 1, option + 1)),
+                None => Ok(()),
+            }
+        })
+    }
+
+    /// Acts for a seat that ran out of time, through the game's `on_timeout` if it has one.
+    /// Otherwise prompts get their first option, and turns draw from `draw_pile` and pass.
+    pub fn timeout(&mut self, seat: usize, draw_pile: Option<usize>) -> rlua::Result<()> {
+        self.transaction(|this| this.run_timeout(seat, draw_pile))
+    }
+
+    fn run_timeout(&self, seat: usize, draw_pile: Option<usize>) -> rlua::Result<()> {
+        let on_timeout = self.lua.context(|ctx| {
+            match ctx
+                .globals()
+                .get::<_, Option<rlua::Function>>("on_timeout")?
+            {
+                Some(on_timeout) => on_timeout.call::<_, ()>(seat + 1).map(|_| true),
+                None => Ok(false),
+            }
+        })?;
+        if on_timeout {
+            return Ok(());
+        }
+        if self.prompted()? == Some(seat) {
+            return self.run_answer(seat, 0);
+        }
+        if let Some(pile) = draw_pile {
+            // Failing to draw, like from an empty pile, still passes the turn
+            if let Ok(((), saved)) = self.attempt(|| self.run_click(seat, PileId::Common(pile))) {
+                self.forget(saved)?;
+            }
+        }
+        // The draw might have passed the turn already
+        if self.turn()? == Some(seat) {
+            self.lua.context(|ctx| {
+                ctx.globals()
+                    .get::<_, rlua::Function>("next_turn")?
+                    .call::<_, ()>(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Runs the `on_click` handler of a pile on behalf of a seat
+    pub fn click(&mut self, seat: usize, pile: PileId) -> rlua::Result<()> {
+        self.transaction(|this| this.run_click(seat, pile))
+    }
+
+    fn run_click(&self, seat: usize, pile: PileId) -> rlua::Result<()> {
+        self.lua.context(|ctx| {
+            let state: Table = ctx.named_registry_value(STATE)?;
+            let seats: Table = state.get("players")?;
+            let own: Table = seats.get(seat + 1)?;
+            let target: Option<Table> = match pile {
+                PileId::Common(i) => state.get::<_, Table>("piles")?.get(i + 1)?,
+                PileId::Own(i) => own.get(i + 1)?,
+            };
+            let target = target.ok_or_else(|| rlua::Error::RuntimeError("No such pile".into()))?;
+            if let Some(on_click) = target.get::<_, Option<rlua::Function>>("on_click")? {
+                match on_click.call((target, own, seat + 1))? {
+                    rlua::Value::Table(player_piles) => seats.set(seat + 1, player_piles)?,
+                    rlua::Value::Nil => (),
+                    _ => {
+                        return Err(rlua::Error::RuntimeError(
+                            "on_click should return the player piles".into(),
+                        ))
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    pub fn view(&self, viewer: Viewer) -> rlua::Result<GameView> {
+        self.lua.context(|ctx| {
+            let state: Table = ctx.named_registry_value(STATE)?;
+            let omniscient = viewer == Viewer::Omniscient;
+            let piles = state
+                .get::<_, Table>("piles")?
+                .sequence_values()
+                .map(|pile| pile_view(pile?, true, omniscient))
+                .collect::<rlua::Result<_>>()?;
+            let player_piles = state
+                .get::<_, Table>("players")?
+                .sequence_values::<Table>()
+                .enumerate()
+                .map(|(seat, piles)| {
+                    piles?
+                        .sequence_values()
+                        .map(|pile| pile_view(pile?, viewer == Viewer::Seat(seat), omniscient))
+                        .collect()
+                })
+                .collect::<rlua::Result<_>>()?;
+            let prompt = match state.get::<_, Option<Table>>("prompt")? {
+                Some(prompt)
+                    if omniscient
+                        || viewer
+                            == Viewer::Seat(lua_index(prompt.get("player")?, "prompt.player")?) =>
+                {
+                    Some(Prompt {
+                        seat: lua_index(prompt.get("player")?, "prompt.player")?,
+                        question: prompt.get("question")?,
+                        options: prompt
+                            .get::<_, Table>("options")?
+                            .sequence_values()
+                            .collect::<rlua::Result<_>>()?,
+                    })
+                }
+                _ => None,
+            };
+            Ok(GameView {
+                seat: match viewer {
+                    Viewer::Seat(seat) => Some(seat),
+                    _ => None,
+                },
+                piles,
+                player_piles,
+                turn: state
+                    .get::<_, Option<usize>>("turn")?
+                    .map(|x| lua_index(x, "turn"))
+                    .transpose()?,
+                prompt,
+            })
+        })
+    }
+}
+
+/// The message of a lua error, without the traceback
+pub fn lua_message(e: &rlua::Error) -> String {
+    match e {
+        rlua::Error::CallbackError { cause, .. } => lua_message(cause),
+        rlua::Error::RuntimeError(message) => {
+            message.lines().next().unwrap_or_default().to_string()
+        }
+        e => e.to_string(),
+    }
+}
+
+/// Lua counts from 1, a 0 is an error of the game rather than something to wrap around
+fn lua_index(i: usize, what: &str) -> rlua::Result<usize> {
+    i.checked_sub(1)
+        .ok_or_else(|| rlua::Error::RuntimeError(format!("{} starts at 1", what)))
+}
+
+/// Reads an action written in lua as `{common = i}` or `{own = i}`
+pub fn pile_id(action: Table) -> rlua::Result<PileId> {
+    if let Some(i) = action.get::<_, Option<usize>>("common")? {
+        Ok(PileId::Common(lua_index(i, "common")?))
+    } else if let Some(i) = action.get::<_, Option<usize>>("own")? {
+        Ok(PileId::Own(lua_index(i, "own")?))
+    } else {
+        Err(rlua::Error::RuntimeError(
+            "actions should be {common = i} or {own = i}".into(),
+        ))
+    }
+}
+
+/// Face down piles are only shown to omniscient viewers, the rest to whoever they're visible to
+fn pile_view(pile: Table, visible: bool, omniscient: bool) -> rlua::Result<PileView> {
+    let face_down = pile.get::<_, Option<bool>>("face_down")?.unwrap_or(false);
+    let show = omniscient || (visible && !face_down);
+    let cards = match pile.get::<_, Option<Table>>("cards")? {
+        Some(cards) => cards
+            .sequence_values()
+            .map(|card| {
+                let card = card?;
+                Ok(if show { Some(card_view(card)?) } else { None })
+            })
+            .collect::<rlua::Result<_>>()?,
+        None => Vec::new(),
+    };
+    Ok(PileView { face_down, cards })
+}
+
+fn card_view(card: Table) -> rlua::Result<Card> {
+    let mut image = String::new();
+    let mut properties = BTreeMap::new();
+    for pair in card.pairs::<String, rlua::Value>() {
+        let (k, v) = pair?;
+        let v = match v {
+            rlua::Value::String(s) => s.to_str()?.to_string(),
+            rlua::Value::Integer(i) => i.to_string(),
+            rlua::Value::Number(n) => n.to_string(),
+            rlua::Value::Boolean(b) => b.to_string(),
+            _ => continue,
+        };
+        if k == "image" {
+            image = v;
+        } else {
+            properties.insert(k, v);
+        }
+    }
+    Ok(Card { image, properties })
 }
 
 impl std::fmt::Display for ThreadSafeGame {
@@ -192,3 +865,76 @@ impl std::fmt::Display for ThreadSafeGame {
 //         }
 //     }
 // }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Loads a game from its source, written to a file of its own
+    pub(crate) fn game(source: &str) -> ThreadSafeGame {
+        let folder = std::env::temp_dir().join(format!("cards-{}", cards_protocol::Uuid::new_v4()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let file = folder.join("game.lua");
+        std::fs::write(&file, source).unwrap();
+        let game = Game::try_load(&file).unwrap().thread_safe();
+        std::fs::remove_dir_all(&folder).ok();
+        game
+    }
+
+    const UNDO_GAME: &str = r#"
+        name = "UNDO"
+        version = "1.0.0"
+        players = {2, 2}
+
+        function setup(players)
+            local fails = {face_down = false, cards = {}}
+            function fails:on_click(player_piles)
+                irreversible()
+                error("it fails after all")
+            end
+            local works = {face_down = false, cards = {}}
+            function works:on_click(player_piles)
+                return player_piles
+            end
+            return {fails, works}, {}
+        end
+    "#;
+
+    #[test]
+    fn a_failed_irreversible_action_doesnt_clear_the_history() {
+        let mut instance = game(UNDO_GAME).instance();
+        instance.setup(2, &BTreeMap::new()).unwrap();
+        instance.click(0, PileId::Common(1)).unwrap();
+        assert!(instance.click(0, PileId::Common(0)).is_err());
+        instance.click(0, PileId::Common(1)).unwrap();
+        assert!(instance.can_undo());
+        instance.undo(false).unwrap();
+        instance.undo(false).unwrap();
+        assert!(!instance.can_undo());
+    }
+
+    /// Clicking hands the turn to a seat that doesn't exist
+    const TURN_ZERO_GAME: &str = r#"
+        name = "TURN ZERO"
+        version = "1.0.0"
+        players = {2, 2}
+
+        function setup(players)
+            local pile = {face_down = false, cards = {}}
+            function pile:on_click(player_piles)
+                game_state().turn = 0
+                return player_piles
+            end
+            return {pile}, {}
+        end
+    "#;
+
+    #[test]
+    fn a_turn_of_0_is_an_error() {
+        let mut instance = game(TURN_ZERO_GAME).instance();
+        instance.setup(2, &BTreeMap::new()).unwrap();
+        assert!(instance.click(0, PileId::Common(0)).is_err());
+        // Rolled back to a turn that can be shown
+        assert_eq!(instance.turn().unwrap(), Some(0));
+    }
+}
@@ -0,0 +1,44 @@
+use std::fs::read_dir;
+
+pub mod auth;
+pub mod bot;
+mod chat;
+pub mod config;
+pub mod game;
+mod metrics;
+pub mod players;
+mod queue;
+mod room;
+pub mod server;
+pub mod stats;
+mod tournament;
+
+/// Looks for a `game.lua` in each folder inside the games folder
+pub fn load_games<P: AsRef<std::path::Path>>(folder: P) -> Vec<game::Game> {
+    let mut games = Vec::new();
+    for folder in read_dir(folder).unwrap().flatten() {
+        if folder.path().is_dir() {
+            for file in read_dir(folder.path()).unwrap().flatten() {
+                if file.file_name().to_str().unwrap() == "game.lua" {
+                    games.push(game::Game::load(file.path()))
+                }
+            }
+        }
+    }
+    games
+}
+
+/// Like `load_games`, failing on the first game that doesn't load instead of panicking
+pub fn try_load_games<P: AsRef<std::path::Path>>(folder: P) -> Result<Vec<game::Game>, String> {
+    let mut games = Vec::new();
+    for folder in read_dir(&folder)
+        .map_err(|e| format!("{}: {}", folder.as_ref().display(), e))?
+        .flatten()
+    {
+        let file = folder.path().join("game.lua");
+        if file.is_file() {
+            games.push(game::Game::try_load(file)?)
+        }
+    }
+    Ok(games)
+}
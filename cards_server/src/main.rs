@@ -1,31 +1,61 @@
-use std::fs::read_dir;
-
-mod game;
-mod server;
+use cards_protocol::ServerTls;
+use cards_server::{
+    auth::Auth,
+    config::{Config, USAGE},
+    load_games, server,
+};
 
 fn main() {
-    tracing::subscriber::set_global_default(cards_subscriber::Subscriber::new(
-        "logs/server",
-        &["cards_server::server"],
-        true,
-    ))
-    .unwrap();
+    if std::env::args().any(|x| x == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let config = Config::from_args(std::env::args().skip(1)).unwrap_or_else(|e| fail(e));
+
+    let targets: Vec<&str> = config.log_targets.iter().map(String::as_str).collect();
+    let subscriber = config.log_filters.iter().fold(
+        cards_subscriber::Subscriber::new(&config.logs, &targets, true),
+        |subscriber, filter| subscriber.filter(filter.filter()),
+    );
+    tracing::subscriber::set_global_default(subscriber.clone()).unwrap();
 
     // Look for each game
-    let mut games = Vec::new();
-    for file in read_dir("games").unwrap() {
-        if let Ok(folder) = file {
-            if folder.path().is_dir() {
-                for file in read_dir(folder.path()).unwrap() {
-                    if let Ok(file) = file {
-                        if file.file_name().to_str().unwrap() == "game.lua" {
-                            games.push(game::Game::load(file.path()))
-                        }
-                    }
-                }
-            }
-        }
+    if !config.games.is_dir() {
+        fail(format!("No games folder at {}", config.games.display()));
     }
+    let games = load_games(&config.games);
     // println!("[{}]", games.iter().map(|x|x.to_string()).rev().fold(String::new(), |s, x| format!("{}, {}", x, s)));
-    server::run(games);
+    let auth = Auth::from_folder(&config.auth)
+        .unwrap_or_else(|e| fail(format!("Unable to load the authentication files: {}", e)));
+    let tls = config.tls.as_ref().map(|tls| {
+        ServerTls::from_pem_files(&tls.cert, &tls.key)
+            .unwrap_or_else(|e| fail(format!("Unable to load the TLS certificate: {}", e)))
+    });
+    // No falling back to another port, clients wouldn't know where to find the server
+    let listener = std::net::TcpListener::bind(config.address)
+        .unwrap_or_else(|e| fail(format!("Unable to listen on {}: {}", config.address, e)));
+    let metrics = config.metrics.map(|addr| {
+        std::net::TcpListener::bind(addr)
+            .unwrap_or_else(|e| fail(format!("Unable to serve the metrics on {}: {}", addr, e)))
+    });
+    let res = server::run(
+        listener,
+        metrics,
+        config,
+        games,
+        auth,
+        tls,
+        subscriber.clone(),
+    );
+    if let Err(e) = subscriber.flush() {
+        eprintln!("Unable to flush the logs: {}", e);
+    }
+    if let Err(e) = res {
+        fail(e);
+    }
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1)
 }
@@ -0,0 +1,399 @@
+//! Counters and histograms of the server, served over HTTP in the Prometheus text format
+
+use crate::game::Games;
+use crate::room::Rooms;
+
+use cards_protocol as proto;
+use smol::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    prelude::*,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+/// Upper bounds of the lua latency buckets, in microseconds
+const LATENCY_BUCKETS: &[u64] = &[
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000,
+];
+/// Upper bounds of the frame size buckets, in bytes
+const SIZE_BUCKETS: &[u64] = &[
+    16, 64, 256, 1_024, 4_096, 16_384, 65_536, 262_144, 1_048_576,
+];
+/// Scrapers that take longer than this to send their request are dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The lua functions of a game that are timed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handler {
+    Setup,
+    LegalActions,
+    Click,
+    Answer,
+    Timeout,
+    Bot,
+}
+
+impl Handler {
+    const ALL: [Handler; 6] = [
+        Handler::Setup,
+        Handler::LegalActions,
+        Handler::Click,
+        Handler::Answer,
+        Handler::Timeout,
+        Handler::Bot,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Handler::Setup => "setup",
+            Handler::LegalActions => "legal_actions",
+            Handler::Click => "click",
+            Handler::Answer => "answer",
+            Handler::Timeout => "timeout",
+            Handler::Bot => "bot",
+        }
+    }
+}
+
+/// Cumulative buckets of whole numbers, shown divided by the scale
+struct Histogram {
+    bounds: &'static [u64],
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [u64]) -> Self {
+        Self {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: u64) {
+        if let Some(i) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The `_bucket`, `_sum` and `_count` lines, with labels like `handler="click"`
+    fn write(&self, out: &mut String, name: &str, labels: &str, scale: f64) {
+        let (braced, prefix) = if labels.is_empty() {
+            (String::new(), String::new())
+        } else {
+            (format!("{{{}}}", labels), format!("{},", labels))
+        };
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name,
+                prefix,
+                *bound as f64 / scale,
+                cumulative
+            )
+            .unwrap();
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, prefix, count).unwrap();
+        writeln!(
+            out,
+            "{}_sum{} {}",
+            name,
+            braced,
+            self.sum.load(Ordering::Relaxed) as f64 / scale
+        )
+        .unwrap();
+        writeln!(out, "{}_count{} {}", name, braced, count).unwrap();
+    }
+}
+
+/// What the server counts while it runs, the gauges are read from the rooms and connections when scraped
+pub struct Metrics {
+    /// Actions played by game, so their rate is the actions per second
+    actions: Mutex<BTreeMap<String, u64>>,
+    lua_latency: Vec<Histogram>,
+    lua_errors: Vec<AtomicU64>,
+    frames_received: Histogram,
+    frames_sent: Histogram,
+    decode_failures: AtomicU64,
+    states_replaced: AtomicU64,
+    too_slow: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            actions: Default::default(),
+            lua_latency: Handler::ALL
+                .iter()
+                .map(|_| Histogram::new(LATENCY_BUCKETS))
+                .collect(),
+            lua_errors: Handler::ALL.iter().map(|_| AtomicU64::new(0)).collect(),
+            frames_received: Histogram::new(SIZE_BUCKETS),
+            frames_sent: Histogram::new(SIZE_BUCKETS),
+            decode_failures: AtomicU64::new(0),
+            states_replaced: AtomicU64::new(0),
+            too_slow: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    /// An action played in a game of that name, by a player, a bot or a timeout
+    pub fn action(&self, game: &str) {
+        *self
+            .actions
+            .lock()
+            .unwrap()
+            .entry(game.to_string())
+            .or_default() += 1;
+    }
+
+    /// Runs a lua handler, timing it and counting its errors
+    pub fn lua<T>(&self, handler: Handler, f: impl FnOnce() -> rlua::Result<T>) -> rlua::Result<T> {
+        let i = handler as usize;
+        let start = Instant::now();
+        let res = f();
+        self.lua_latency[i].observe(start.elapsed().as_micros() as u64);
+        if res.is_err() {
+            self.lua_errors[i].fetch_add(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    /// Everything in the Prometheus text format
+    pub async fn render(
+        &self,
+        server: &proto::ServerProtocol,
+        rooms: &Rooms,
+        games: &Games,
+    ) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP cards_connections Open connections\n");
+        out.push_str("# TYPE cards_connections gauge\n");
+        writeln!(
+            out,
+            "cards_connections {}",
+            server.connections().await.len()
+        )
+        .unwrap();
+
+        // Every loaded game shows up, even without rooms
+        let mut per_game: BTreeMap<String, (usize, usize)> = games
+            .list()
+            .iter()
+            .map(|g| (g.name().clone(), (0, 0)))
+            .collect();
+        for room in rooms.all().await {
+            let (open, started) = per_game.entry(room.game).or_default();
+            *open += 1;
+            if room.started && !room.finished {
+                *started += 1;
+            }
+        }
+        out.push_str("# HELP cards_rooms Open rooms by game\n");
+        out.push_str("# TYPE cards_rooms gauge\n");
+        for (game, (open, _)) in &per_game {
+            writeln!(out, "cards_rooms{{game=\"{}\"}} {}", escape(game), open).unwrap();
+        }
+        out.push_str("# HELP cards_rooms_playing Rooms with a game in progress by game\n");
+        out.push_str("# TYPE cards_rooms_playing gauge\n");
+        for (game, (_, started)) in &per_game {
+            writeln!(
+                out,
+                "cards_rooms_playing{{game=\"{}\"}} {}",
+                escape(game),
+                started
+            )
+            .unwrap();
+        }
+
+        out.push_str("# HELP cards_actions_total Actions played by game\n");
+        out.push_str("# TYPE cards_actions_total counter\n");
+        for (game, count) in self.actions.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "cards_actions_total{{game=\"{}\"}} {}",
+                escape(game),
+                count
+            )
+            .unwrap();
+        }
+
+        out.push_str("# HELP cards_lua_seconds Time spent in the lua handlers of the games\n");
+        out.push_str("# TYPE cards_lua_seconds histogram\n");
+        for handler in Handler::ALL.iter() {
+            self.lua_latency[*handler as usize].write(
+                &mut out,
+                "cards_lua_seconds",
+                &format!("handler=\"{}\"", handler.name()),
+                1_000_000.,
+            );
+        }
+        out.push_str("# HELP cards_lua_errors_total Lua handlers that failed\n");
+        out.push_str("# TYPE cards_lua_errors_total counter\n");
+        for handler in Handler::ALL.iter() {
+            writeln!(
+                out,
+                "cards_lua_errors_total{{handler=\"{}\"}} {}",
+                handler.name(),
+                self.lua_errors[*handler as usize].load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+
+        out.push_str("# HELP cards_frame_bytes Size of the frames, without their length\n");
+        out.push_str("# TYPE cards_frame_bytes histogram\n");
+        self.frames_received
+            .write(&mut out, "cards_frame_bytes", "direction=\"received\"", 1.);
+        self.frames_sent
+            .write(&mut out, "cards_frame_bytes", "direction=\"sent\"", 1.);
+        out.push_str("# HELP cards_decode_failures_total Frames that weren't a valid request\n");
+        out.push_str("# TYPE cards_decode_failures_total counter\n");
+        writeln!(
+            out,
+            "cards_decode_failures_total {}",
+            self.decode_failures.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        out.push_str(
+            "# HELP cards_states_replaced_total Queued states and deltas dropped for a newer state of the room\n",
+        );
+        out.push_str("# TYPE cards_states_replaced_total counter\n");
+        writeln!(
+            out,
+            "cards_states_replaced_total {}",
+            self.states_replaced.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        out.push_str(
+            "# HELP cards_slow_disconnects_total Connections dropped for not reading what was sent\n",
+        );
+        out.push_str("# TYPE cards_slow_disconnects_total counter\n");
+        writeln!(
+            out,
+            "cards_slow_disconnects_total {}",
+            self.too_slow.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        out
+    }
+}
+
+impl proto::Observer for Metrics {
+    fn frame_received(&self, bytes: usize) {
+        self.frames_received.observe(bytes as u64);
+    }
+
+    fn frame_sent(&self, bytes: usize) {
+        self.frames_sent.observe(bytes as u64);
+    }
+
+    fn decode_failed(&self) {
+        self.decode_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn state_replaced(&self) {
+        self.states_replaced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn too_slow(&self) {
+        self.too_slow.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Label values escaped as the text format wants them
+fn escape(x: &str) -> String {
+    x.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answers `GET /metrics` on the listener, meant to be a local address
+pub async fn serve<F, Fut>(listener: TcpListener, render: F)
+where
+    F: Fn() -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = String> + Send,
+{
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving the metrics on http://{}/metrics", addr);
+    }
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Unable to accept a metrics connection: {}", e);
+                continue;
+            }
+        };
+        let render = render.clone();
+        smol::spawn(async move {
+            if let Err(e) = respond(stream, render).await {
+                warn!("Unable to serve the metrics: {}", e);
+            }
+        })
+        .detach();
+    }
+}
+
+async fn respond<F, Fut>(mut stream: TcpStream, render: F) -> std::io::Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = String>,
+{
+    // Only the request line matters, the headers are read to be polite
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|x| x == b"\r\n\r\n") && request.len() < 8 * 1024 {
+        let read = stream
+            .read(&mut buf)
+            .or(async {
+                smol::Timer::after(REQUEST_TIMEOUT).await;
+                Err(std::io::ErrorKind::TimedOut.into())
+            })
+            .await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render().await;
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        (Some("GET"), Some(_)) => {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        }
+        _ => "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await
+}
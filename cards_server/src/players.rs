@@ -0,0 +1,141 @@
+use crate::auth::Identity;
+use cards_protocol::{Rejection, Uuid};
+
+use smol::lock::RwLock;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+pub const MAX_NAME_LENGTH: usize = 32;
+
+struct Player {
+    name: String,
+    /// Logged in with an account, the name can't change
+    verified: bool,
+    admin: bool,
+}
+
+/// The names connections go by, stats are kept under them
+#[derive(Clone, Default)]
+pub struct Players {
+    names: Arc<RwLock<HashMap<Uuid, Player>>>,
+    /// Account names, only taken by logging in
+    reserved: Arc<HashSet<String>>,
+}
+
+impl Players {
+    pub fn new(reserved: HashSet<String>) -> Self {
+        Self {
+            names: Default::default(),
+            reserved: Arc::new(reserved),
+        }
+    }
+
+    /// Names are unique among the connected players
+    pub async fn set_name(&self, conn: Uuid, name: String) -> Result<(), Rejection> {
+        let name = name.trim().to_string();
+        if name.is_empty()
+            || name.chars().count() > MAX_NAME_LENGTH
+            || name.chars().any(char::is_control)
+        {
+            return Err(Rejection::InvalidName);
+        }
+        let mut names = self.names.write().await;
+        if names.get(&conn).is_some_and(|p| p.verified) {
+            return Err(Rejection::NameLocked);
+        }
+        if self.reserved.contains(&name) || names.iter().any(|(c, p)| p.name == name && c != &conn)
+        {
+            return Err(Rejection::NameTaken);
+        }
+        names.insert(
+            conn,
+            Player {
+                name,
+                verified: false,
+                admin: false,
+            },
+        );
+        Ok(())
+    }
+
+    /// Gives the connection the name of the account it logged in with, and its role.
+    /// Identities without a name leave the connection as it was
+    pub async fn login(&self, conn: Uuid, identity: Identity) -> Result<(), Rejection> {
+        let name = match identity.name {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        let mut names = self.names.write().await;
+        if names.iter().any(|(c, p)| p.name == name && c != &conn) {
+            return Err(Rejection::NameTaken);
+        }
+        names.insert(
+            conn,
+            Player {
+                name,
+                verified: true,
+                admin: identity.admin,
+            },
+        );
+        Ok(())
+    }
+
+    pub async fn name(&self, conn: &Uuid) -> Option<String> {
+        self.names.read().await.get(conn).map(|p| p.name.clone())
+    }
+
+    /// The name of the account the connection logged in with, names taken with `set_name` don't count
+    pub async fn verified_name(&self, conn: &Uuid) -> Option<String> {
+        self.names
+            .read()
+            .await
+            .get(conn)
+            .filter(|p| p.verified)
+            .map(|p| p.name.clone())
+    }
+
+    /// Every connection with a name
+    pub async fn names(&self) -> HashMap<Uuid, String> {
+        self.names
+            .read()
+            .await
+            .iter()
+            .map(|(conn, p)| (*conn, p.name.clone()))
+            .collect()
+    }
+
+    /// With accounts, names that aren't logged in can be taken by anyone once their player leaves
+    pub fn has_accounts(&self) -> bool {
+        !self.reserved.is_empty()
+    }
+
+    pub async fn is_admin(&self, conn: &Uuid) -> bool {
+        self.names.read().await.get(conn).is_some_and(|p| p.admin)
+    }
+
+    /// The connection going by a name
+    pub async fn conn(&self, name: &str) -> Option<Uuid> {
+        self.names
+            .read()
+            .await
+            .iter()
+            .find(|(_, p)| p.name == name)
+            .map(|(conn, _)| *conn)
+    }
+
+    /// The connection logged in with the account
+    pub async fn verified_conn(&self, name: &str) -> Option<Uuid> {
+        self.names
+            .read()
+            .await
+            .iter()
+            .find(|(_, p)| p.verified && p.name == name)
+            .map(|(conn, _)| *conn)
+    }
+
+    pub async fn remove(&self, conn: &Uuid) {
+        self.names.write().await.remove(conn);
+    }
+}
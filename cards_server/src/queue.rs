@@ -0,0 +1,146 @@
+use cards_protocol::{Event, Rejection, Reply, Uuid};
+
+use smol::lock::Mutex;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Players waiting for a game of one size, balanced queues group players of similar rating
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct QueueKey {
+    pub game: String,
+    pub players: usize,
+    pub balanced: bool,
+}
+
+/// Players waiting to be put in a room automatically, each connection waits in one queue at most
+#[derive(Clone, Default)]
+pub struct Queue {
+    inner: Arc<Mutex<HashMap<QueueKey, Waiting>>>,
+}
+
+#[derive(Default)]
+struct Waiting {
+    entries: Vec<Entry>,
+    last_match: Option<Instant>,
+    /// Average time between two matches of this queue, unknown until two have been formed
+    interval: Option<Duration>,
+}
+
+struct Entry {
+    conn: Uuid,
+    rating: f64,
+}
+
+impl Queue {
+    /// Puts the connection in a queue, taking it out of the one it was in.
+    /// Returns the position notices and the players of a match, if one could be formed.
+    pub async fn enqueue(
+        &self,
+        conn: Uuid,
+        key: QueueKey,
+        rating: f64,
+    ) -> (Vec<(Uuid, Reply)>, Option<Vec<Uuid>>) {
+        let mut queues = self.inner.lock().await;
+        let mut notices = remove(&mut queues, &conn);
+        let waiting = queues.entry(key.clone()).or_default();
+        waiting.entries.push(Entry { conn, rating });
+        let group = waiting.form(&key);
+        notices.extend(waiting.positions(&key));
+        (notices, group)
+    }
+
+    /// Returns the position notices for the players that were behind
+    pub async fn dequeue(&self, conn: &Uuid) -> Result<Vec<(Uuid, Reply)>, Rejection> {
+        let mut queues = self.inner.lock().await;
+        if !queues
+            .values()
+            .any(|x| x.entries.iter().any(|e| &e.conn == conn))
+        {
+            return Err(Rejection::NotQueued);
+        }
+        Ok(remove(&mut queues, conn))
+    }
+}
+
+/// Takes the connection out of every queue, returns the position notices for the rest
+fn remove(queues: &mut HashMap<QueueKey, Waiting>, conn: &Uuid) -> Vec<(Uuid, Reply)> {
+    let mut notices = Vec::new();
+    for (key, waiting) in queues.iter_mut() {
+        let len = waiting.entries.len();
+        waiting.entries.retain(|x| &x.conn != conn);
+        if waiting.entries.len() != len {
+            notices.extend(waiting.positions(key));
+        }
+    }
+    notices
+}
+
+impl Waiting {
+    /// Takes the players for a match out of the queue if there are enough.
+    /// The player waiting the longest is always in it, balanced queues fill it with the closest ratings.
+    fn form(&mut self, key: &QueueKey) -> Option<Vec<Uuid>> {
+        if self.entries.len() < key.players {
+            return None;
+        }
+        let picked: Vec<usize> = if key.balanced {
+            let first = self.entries[0].rating;
+            let mut others: Vec<usize> = (1..self.entries.len()).collect();
+            others.sort_by(|a, b| {
+                let a = (self.entries[*a].rating - first).abs();
+                let b = (self.entries[*b].rating - first).abs();
+                a.partial_cmp(&b).unwrap()
+            });
+            std::iter::once(0)
+                .chain(others.into_iter().take(key.players - 1))
+                .collect()
+        } else {
+            (0..key.players).collect()
+        };
+        let group = picked.iter().map(|i| self.entries[*i].conn).collect();
+        let mut i = 0;
+        self.entries.retain(|_| {
+            i += 1;
+            !picked.contains(&(i - 1))
+        });
+        let now = Instant::now();
+        if let Some(last) = self.last_match {
+            let since = now - last;
+            self.interval = Some(match self.interval {
+                Some(interval) => (interval * 3 + since) / 4,
+                None => since,
+            });
+        }
+        self.last_match = Some(now);
+        Some(group)
+    }
+
+    /// The position and estimated wait of everyone in the queue
+    fn positions(&self, key: &QueueKey) -> Vec<(Uuid, Reply)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                let eta = match (self.interval, self.last_match) {
+                    (Some(interval), Some(last)) => {
+                        let matches = (i / key.players) as u32 + 1;
+                        Some(
+                            (interval * matches)
+                                .saturating_sub(last.elapsed())
+                                .as_secs(),
+                        )
+                    }
+                    _ => None,
+                };
+                let event = Event::QueuePosition {
+                    game: key.game.clone(),
+                    position: i + 1,
+                    eta,
+                };
+                (entry.conn, Reply::Event(event))
+            })
+            .collect()
+    }
+}
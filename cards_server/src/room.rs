@@ -0,0 +1,1450 @@
+use crate::bot::Bot;
+use crate::chat::RateLimiter;
+use crate::game::{lua_message, Instance, ThreadSafeGame, Viewer};
+use crate::metrics::{Handler, Metrics};
+use crate::players::Players;
+use crate::stats::{MatchResult, Stats};
+
+use cards_protocol as proto;
+use proto::{
+    Event, GameView, PileId, Rejection, Reply, RoomDetails, RoomInfo, RoomSettings, Seat,
+    TimerKind, Uuid, Visibility,
+};
+use rand::{seq::SliceRandom, thread_rng};
+use serde::{Deserialize, Serialize};
+
+use smol::{
+    channel::Sender,
+    lock::{Mutex, MutexGuard, RwLock},
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{self, File},
+    future::Future,
+    io,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use tracing::{instrument, warn};
+
+pub struct Room {
+    id: Uuid,
+    game: ThreadSafeGame,
+    host: Uuid,
+    settings: RoomSettings,
+    /// Seats in order, a seat is emptied when its player leaves a started game
+    players: Vec<Seat>,
+    /// The lua state of each bot seat
+    bots: HashMap<usize, Bot>,
+    /// Spectators and whether they get the omniscient view
+    spectators: HashMap<Uuid, bool>,
+    muted: HashSet<Uuid>,
+    /// Connections and player names the host banned
+    banned: HashSet<Uuid>,
+    banned_names: HashSet<String>,
+    invite: Option<String>,
+    instance: Option<Instance>,
+    /// Whether the game is over, in which case players can join and the host can start a rematch
+    finished: bool,
+    /// Seats the game was set up with, the ones taken after it finished wait for the rematch
+    seated: usize,
+    /// Who the running timer is waiting for, with the generation that identifies it
+    timer: Option<(u64, TimerKind, usize)>,
+    timer_generation: u64,
+    /// Counts the changes to the game, so that scheduled bots notice they're late
+    version: u64,
+    /// The last view sent to each member, the next one is sent as the changes to it
+    sent: HashMap<Uuid, SentView>,
+    delayed: DelayLine,
+    undo: Option<UndoVote>,
+    stats: Stats,
+    /// Where finished matches are reported besides the stats, like to the tournaments
+    reports: Sender<MatchResult>,
+    metrics: Arc<Metrics>,
+}
+
+/// A view with the number it was sent with
+struct SentView {
+    seq: u64,
+    view: GameView,
+}
+
+/// A proposal to take back actions, it passes when every other player accepts it
+struct UndoVote {
+    seat: usize,
+    whole_turn: bool,
+    accepted: HashSet<usize>,
+}
+
+/// Characters of invite codes, without the ones that are easy to confuse
+const INVITE_CHARACTERS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const INVITE_LENGTH: usize = 6;
+
+/// How long bots wait before acting, so that players can follow them
+const BOT_DELAY: Duration = Duration::from_secs(1);
+
+impl Room {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn info(&self) -> RoomInfo {
+        RoomInfo {
+            id: self.id,
+            game: self.game.name().clone(),
+            players: self.players.iter().filter_map(Seat::player).count(),
+            bots: self.players.iter().filter(|x| **x == Seat::Bot).count(),
+            spectators: self.spectators.len(),
+            started: self.instance.is_some(),
+            finished: self.finished,
+            locked: matches!(self.settings.visibility, Visibility::Password(_)),
+            options: self.settings.options.clone(),
+        }
+    }
+
+    fn seat(&self, conn: &Uuid) -> Option<usize> {
+        self.players.iter().position(|x| x.player() == Some(conn))
+    }
+
+    pub fn is_member(&self, conn: &Uuid) -> bool {
+        self.seat(conn).is_some() || self.spectators.contains_key(conn)
+    }
+
+    fn members(&self) -> impl Iterator<Item = &Uuid> {
+        self.players
+            .iter()
+            .filter_map(Seat::player)
+            .chain(self.spectators.keys())
+    }
+
+    pub fn member_list(&self) -> Reply {
+        Reply::Members {
+            host: self.host,
+            players: self.players.clone(),
+            spectators: self.spectators.keys().copied().collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.players.iter().all(|x| x.player().is_none()) && self.spectators.is_empty()
+    }
+
+    /// Unlisted rooms are only listed for their members
+    fn visible_to(&self, conn: &Uuid) -> bool {
+        self.settings.visibility != Visibility::Unlisted || self.is_member(conn)
+    }
+
+    pub fn check_ban(&self, conn: &Uuid, name: Option<&str>) -> Result<(), Rejection> {
+        if self.banned.contains(conn) || name.is_some_and(|x| self.banned_names.contains(x)) {
+            Err(Rejection::Banned)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether a connection may join or spectate, members can always come back as something else
+    pub fn admit(
+        &self,
+        conn: &Uuid,
+        name: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<(), Rejection> {
+        if self.is_member(conn) {
+            return Ok(());
+        }
+        self.check_ban(conn, name)?;
+        match &self.settings.visibility {
+            Visibility::Password(x) if Some(x.as_str()) != password => {
+                Err(Rejection::WrongPassword)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn join(&mut self, conn: Uuid) -> Result<usize, Rejection> {
+        if let Some(seat) = self.seat(&conn) {
+            return Ok(seat);
+        }
+        if self.is_live() {
+            return Err(Rejection::AlreadyStarted);
+        }
+        if self.players.len() >= self.game.manifest().players.1 {
+            return Err(Rejection::RoomFull);
+        }
+        self.spectators.remove(&conn);
+        self.players.push(Seat::Player(conn));
+        Ok(self.players.len() - 1)
+    }
+
+    pub fn add_bot(&mut self, conn: &Uuid) -> Result<(), Rejection> {
+        if &self.host != conn {
+            return Err(Rejection::NotHost);
+        }
+        if self.instance.is_some() {
+            return Err(Rejection::AlreadyStarted);
+        }
+        if self.players.len() >= self.game.manifest().players.1 {
+            return Err(Rejection::RoomFull);
+        }
+        let bot = match self.game.bot() {
+            Some(bot) => bot.map_err(|e| Rejection::Game(e.to_string()))?,
+            None => return Err(Rejection::NoBot),
+        };
+        self.bots.insert(self.players.len(), bot);
+        self.players.push(Seat::Bot);
+        Ok(())
+    }
+
+    pub fn spectate(&mut self, conn: Uuid, omniscient: bool) -> Result<(), Rejection> {
+        if !self.game.manifest().spectators {
+            return Err(Rejection::SpectatorsDisabled);
+        }
+        if omniscient && self.settings.omniscient_delay.is_none() {
+            return Err(Rejection::OmniscientDisabled);
+        }
+        if self.seat(&conn).is_some() {
+            return Err(Rejection::AlreadySeated);
+        }
+        self.spectators.insert(conn, omniscient);
+        Ok(())
+    }
+
+    /// Returns whether the room is now empty
+    pub fn leave(&mut self, conn: &Uuid) -> bool {
+        self.muted.remove(conn);
+        if self.spectators.remove(conn).is_none() {
+            if let Some(seat) = self.seat(conn) {
+                if self.instance.is_none() {
+                    self.remove_seat(seat);
+                } else {
+                    self.players[seat] = Seat::Empty;
+                    if self.settings.bots_take_over {
+                        match self.game.bot() {
+                            Some(Ok(bot)) => {
+                                self.bots.insert(seat, bot);
+                                self.players[seat] = Seat::Bot;
+                            }
+                            Some(Err(e)) => warn!("Unable to load the bot: {}", e),
+                            None => (),
+                        }
+                    }
+                }
+            }
+        }
+        if &self.host == conn {
+            // Spectators only host a room with nobody seated
+            let host = self
+                .players
+                .iter()
+                .filter_map(Seat::player)
+                .chain(self.spectators.keys())
+                .next();
+            if let Some(host) = host {
+                self.host = *host;
+            }
+        }
+        self.is_empty()
+    }
+
+    fn remove_seat(&mut self, seat: usize) {
+        self.players.remove(seat);
+        // Bots after the seat move one place up
+        self.bots = self
+            .bots
+            .drain()
+            .map(|(i, bot)| if i > seat { (i - 1, bot) } else { (i, bot) })
+            .collect();
+    }
+
+    /// Starts the game, or a rematch with the same seats once it is finished
+    pub fn start(&mut self, conn: &Uuid) -> Result<(), Rejection> {
+        if &self.host != conn {
+            return Err(Rejection::NotHost);
+        }
+        if self.instance.is_some() && !self.finished {
+            return Err(Rejection::AlreadyStarted);
+        }
+        // Players that left the last game don't keep their seat for the rematch
+        while let Some(seat) = self.players.iter().position(|x| *x == Seat::Empty) {
+            self.remove_seat(seat);
+        }
+        if self.players.len() < self.game.manifest().players.0 {
+            return Err(Rejection::NotEnoughPlayers);
+        }
+        let instance = self.game.instance();
+        self.metrics
+            .lua(Handler::Setup, || {
+                instance.setup(self.players.len(), &self.settings.options)
+            })
+            .map_err(|e| Rejection::Game(e.to_string()))?;
+        self.instance = Some(instance);
+        self.finished = false;
+        self.seated = self.players.len();
+        self.timer = None;
+        self.undo = None;
+        Ok(())
+    }
+
+    /// The instance, as long as the game is being played
+    fn playing(&mut self) -> Result<&mut Instance, Rejection> {
+        match &mut self.instance {
+            Some(_) if self.finished => Err(Rejection::Finished),
+            Some(instance) => Ok(instance),
+            None => Err(Rejection::NotStarted),
+        }
+    }
+
+    pub fn click(&mut self, conn: &Uuid, pile: PileId) -> Result<(), Rejection> {
+        let seat = match self.seat(conn) {
+            Some(seat) => seat,
+            None if self.spectators.contains_key(conn) => return Err(Rejection::Spectating),
+            None => return Err(Rejection::NotInRoom),
+        };
+        let metrics = self.metrics.clone();
+        let game = self.game.name().clone();
+        let instance = self.playing()?;
+        let legal = metrics
+            .lua(Handler::LegalActions, || instance.legal_actions(seat))
+            .map_err(|e| Rejection::Game(e.to_string()))?;
+        if !legal.contains(&pile) {
+            return Err(Rejection::IllegalAction);
+        }
+        metrics
+            .lua(Handler::Click, || instance.click(seat, pile))
+            .map_err(|e| Rejection::ActionFailed(lua_message(&e)))?;
+        metrics.action(&game);
+        Ok(())
+    }
+
+    pub fn legal_actions(&self, conn: &Uuid) -> Result<Vec<PileId>, Rejection> {
+        let seat = match self.seat(conn) {
+            Some(seat) => seat,
+            None if self.spectators.contains_key(conn) => return Err(Rejection::Spectating),
+            None => return Err(Rejection::NotInRoom),
+        };
+        if self.finished {
+            return Err(Rejection::Finished);
+        }
+        let instance = self.instance.as_ref().ok_or(Rejection::NotStarted)?;
+        self.metrics
+            .lua(Handler::LegalActions, || instance.legal_actions(seat))
+            .map_err(|e| Rejection::Game(e.to_string()))
+    }
+
+    pub fn answer(&mut self, conn: &Uuid, option: usize) -> Result<(), Rejection> {
+        let seat = match self.seat(conn) {
+            Some(seat) => seat,
+            None if self.spectators.contains_key(conn) => return Err(Rejection::Spectating),
+            None => return Err(Rejection::NotInRoom),
+        };
+        let metrics = self.metrics.clone();
+        let game = self.game.name().clone();
+        let instance = self.playing()?;
+        metrics
+            .lua(Handler::Answer, || instance.answer(seat, option))
+            .map_err(|e| Rejection::ActionFailed(lua_message(&e)))?;
+        metrics.action(&game);
+        Ok(())
+    }
+
+    /// The configured time limit, the room settings take precedence over the game
+    fn time_limit(&self, kind: TimerKind) -> Option<Duration> {
+        let manifest = self.game.manifest();
+        let limit = match kind {
+            TimerKind::Turn => self.settings.turn_time.or(manifest.turn_time),
+            TimerKind::Prompt => self.settings.prompt_time.or(manifest.prompt_time),
+        };
+        limit.filter(|x| *x > 0).map(Duration::from_secs)
+    }
+
+    /// Starts a new timer if the seat that has to act changed.
+    /// Returns its generation, how long it lasts and the notices for the members.
+    pub fn restart_timer(&mut self) -> Option<(u64, Duration, Vec<Outgoing>)> {
+        let instance = self.instance.as_ref().filter(|_| !self.finished)?;
+        let waiting = match (instance.prompted(), instance.turn()) {
+            (Ok(Some(seat)), _) => Some((TimerKind::Prompt, seat)),
+            (Ok(None), Ok(Some(seat))) => Some((TimerKind::Turn, seat)),
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("Unable to read the turn: {}", e);
+                None
+            }
+            _ => None,
+        };
+        if waiting == self.timer.map(|(_, kind, seat)| (kind, seat)) {
+            return None;
+        }
+        self.timer = None;
+        let (kind, seat) = waiting?;
+        let limit = self.time_limit(kind)?;
+        self.timer_generation += 1;
+        self.timer = Some((self.timer_generation, kind, seat));
+        let reply = Reply::Event(Event::TimerStarted {
+            room: self.id,
+            seat,
+            kind,
+            deadline: SystemTime::now() + limit,
+        });
+        Some((self.timer_generation, limit, self.to_members(reply)))
+    }
+
+    /// Acts for the seat the timer was waiting for, if it is still the running timer
+    pub fn timeout(&mut self, generation: u64) -> bool {
+        let seat = match (self.timer, &self.instance) {
+            (Some((g, _, seat)), Some(_)) if g == generation => seat,
+            _ => return false,
+        };
+        self.timer = None;
+        if let Some(instance) = &mut self.instance {
+            let draw_pile = self.game.manifest().draw_pile;
+            match self
+                .metrics
+                .lua(Handler::Timeout, || instance.timeout(seat, draw_pile))
+            {
+                Ok(()) => self.metrics.action(self.game.name()),
+                Err(e) => warn!("Timeout action for seat {} failed: {}", seat, e),
+            }
+        }
+        true
+    }
+
+    /// The bot that has to act now, if any
+    fn waiting_bot(&self) -> Option<usize> {
+        let instance = self.instance.as_ref().filter(|_| !self.finished)?;
+        let seat = match instance.prompted() {
+            Ok(Some(seat)) => seat,
+            _ => instance.turn().ok()??,
+        };
+        if self.bots.contains_key(&seat) {
+            Some(seat)
+        } else {
+            None
+        }
+    }
+
+    /// Lets the bot that has to act play, unless the game changed since it was scheduled.
+    /// Returns whether the bot changed the game.
+    pub fn bot_act(&mut self, version: u64) -> bool {
+        if version != self.version {
+            return false;
+        }
+        let seat = match self.waiting_bot() {
+            Some(seat) => seat,
+            None => return false,
+        };
+        let instance = match &mut self.instance {
+            Some(instance) => instance,
+            None => return false,
+        };
+        let bot = &self.bots[&seat];
+        let res = self.metrics.lua(Handler::Bot, || {
+            instance
+                .view(Viewer::Seat(seat))
+                .and_then(|view| match &view.prompt {
+                    Some(prompt) => {
+                        let option = bot.answer_prompt(&view, prompt)?;
+                        instance.answer(seat, option).map(|_| true)
+                    }
+                    None => {
+                        let legal = instance.legal_actions(seat)?;
+                        match bot.choose_action(&view, &legal)? {
+                            Some(pile) if legal.contains(&pile) => {
+                                instance.click(seat, pile).map(|_| true)
+                            }
+                            Some(pile) => Err(rlua::Error::RuntimeError(format!(
+                                "{:?} is not a legal action",
+                                pile
+                            ))),
+                            None => Ok(false),
+                        }
+                    }
+                })
+        });
+        match res {
+            Ok(true) => {
+                self.metrics.action(self.game.name());
+                true
+            }
+            Ok(false) => false,
+            Err(e) => {
+                warn!("Bot in seat {} failed: {}", seat, e);
+                false
+            }
+        }
+    }
+
+    /// The message for every member, the length is checked by the caller.
+    /// Messages that couldn't be sent anyway don't count towards the rate limit
+    pub fn chat(
+        &self,
+        conn: &Uuid,
+        text: String,
+        limiter: &mut RateLimiter,
+    ) -> Result<Vec<Outgoing>, Rejection> {
+        if !self.is_member(conn) {
+            return Err(Rejection::NotInRoom);
+        }
+        if self.muted.contains(conn) {
+            return Err(Rejection::Muted);
+        }
+        if !limiter.allow() {
+            return Err(Rejection::RateLimited);
+        }
+        let reply = Reply::Event(Event::ChatMessage {
+            room: self.id,
+            from: *conn,
+            text,
+        });
+        Ok(self.to_members(reply))
+    }
+
+    /// The same reply for every member
+    fn to_members(&self, reply: Reply) -> Vec<Outgoing> {
+        self.members()
+            .map(|conn| Outgoing {
+                conn: *conn,
+                reply: reply.clone(),
+                delay: None,
+            })
+            .collect()
+    }
+
+    /// Starts a vote to undo, which is applied right away if there's no other player to ask.
+    /// Returns the notices for the members and whether the game changed.
+    pub fn propose_undo(
+        &mut self,
+        conn: &Uuid,
+        whole_turn: bool,
+    ) -> Result<(Vec<Outgoing>, bool), Rejection> {
+        let seat = match self.seat(conn) {
+            Some(seat) => seat,
+            None if self.spectators.contains_key(conn) => return Err(Rejection::Spectating),
+            None => return Err(Rejection::NotInRoom),
+        };
+        if !self.playing()?.can_undo() {
+            return Err(Rejection::NothingToUndo);
+        }
+        if self.undo.is_some() {
+            return Err(Rejection::UndoPending);
+        }
+        self.undo = Some(UndoVote {
+            seat,
+            whole_turn,
+            accepted: HashSet::new(),
+        });
+        let mut notices = self.to_members(Reply::Event(Event::UndoProposed {
+            room: self.id,
+            seat,
+            whole_turn,
+        }));
+        let applied = self.resolve_undo(&mut notices);
+        Ok((notices, applied))
+    }
+
+    /// Returns the notices for the members and whether the game changed
+    pub fn vote_undo(
+        &mut self,
+        conn: &Uuid,
+        accept: bool,
+    ) -> Result<(Vec<Outgoing>, bool), Rejection> {
+        let seat = match self.seat(conn) {
+            Some(seat) => seat,
+            None if self.spectators.contains_key(conn) => return Err(Rejection::Spectating),
+            None => return Err(Rejection::NotInRoom),
+        };
+        let vote = match &mut self.undo {
+            Some(vote) if vote.seat != seat => vote,
+            _ => return Err(Rejection::NoUndoPending),
+        };
+        if !accept {
+            self.undo = None;
+            let notices = self.to_members(Reply::Event(Event::UndoResolved {
+                room: self.id,
+                accepted: false,
+            }));
+            return Ok((notices, false));
+        }
+        vote.accepted.insert(seat);
+        let mut notices = Vec::new();
+        let applied = self.resolve_undo(&mut notices);
+        Ok((notices, applied))
+    }
+
+    /// Applies the pending undo if every player other than the proposer accepted it.
+    /// Bots and players that left don't get a vote.
+    fn resolve_undo(&mut self, notices: &mut Vec<Outgoing>) -> bool {
+        let (vote, instance) = match (&self.undo, &mut self.instance) {
+            (Some(vote), Some(instance)) => (vote, instance),
+            _ => return false,
+        };
+        let waiting = self.players.iter().enumerate().any(|(seat, x)| {
+            x.player().is_some() && seat != vote.seat && !vote.accepted.contains(&seat)
+        });
+        if waiting {
+            return false;
+        }
+        let applied = match instance.undo(vote.whole_turn) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Undo failed: {}", e);
+                false
+            }
+        };
+        self.undo = None;
+        notices.extend(self.to_members(Reply::Event(Event::UndoResolved {
+            room: self.id,
+            accepted: applied,
+        })));
+        applied
+    }
+
+    /// Ends the game if it is over.
+    /// Returns the notices for the members and the result to record.
+    fn finish(&mut self) -> Option<(Vec<Outgoing>, MatchResult)> {
+        if self.finished {
+            return None;
+        }
+        let outcome = match self.instance.as_ref()?.outcome() {
+            Ok(outcome) => outcome?,
+            Err(e) => {
+                warn!("Unable to check whether the game is over: {}", e);
+                return None;
+            }
+        };
+        self.finished = true;
+        self.timer = None;
+        let notices = self.to_members(Reply::Event(Event::GameOver {
+            room: self.id,
+            standings: outcome.standings.clone(),
+            scores: outcome.scores.clone(),
+        }));
+        let result = MatchResult {
+            room: self.id,
+            game: self.game.name().clone(),
+            version: self.game.version().clone(),
+            players: self.players.clone(),
+            standings: outcome.standings,
+            scores: outcome.scores,
+            finished: SystemTime::now(),
+        };
+        Some((notices, result))
+    }
+
+    /// The result of a game that is given up before it is over, nobody wins it
+    fn abandoned(&self) -> Option<MatchResult> {
+        if !self.is_live() {
+            return None;
+        }
+        Some(MatchResult {
+            room: self.id,
+            game: self.game.name().clone(),
+            version: self.game.version().clone(),
+            players: self.players.clone(),
+            standings: Vec::new(),
+            scores: Vec::new(),
+            finished: SystemTime::now(),
+        })
+    }
+
+    /// Drops a pending undo because the game moved on, returns the notices if there was one
+    fn drop_undo(&mut self) -> Vec<Outgoing> {
+        match self.undo.take() {
+            Some(_) => self.to_members(Reply::Event(Event::UndoResolved {
+                room: self.id,
+                accepted: false,
+            })),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn mute(&mut self, conn: &Uuid, member: Uuid, muted: bool) -> Result<(), Rejection> {
+        if &self.host != conn {
+            return Err(Rejection::NotHost);
+        }
+        if !self.is_member(&member) {
+            return Err(Rejection::NotInRoom);
+        }
+        if muted {
+            self.muted.insert(member);
+        } else {
+            self.muted.remove(&member);
+        }
+        Ok(())
+    }
+
+    /// Removes a member on behalf of the host, returns the notice for the kicked member
+    pub fn kick(&mut self, conn: &Uuid, member: &Uuid) -> Result<Outgoing, Rejection> {
+        if &self.host != conn || member == conn {
+            return Err(Rejection::NotHost);
+        }
+        self.remove(member)
+    }
+
+    /// Removes a member on behalf of an admin, returns the notice for the kicked member
+    pub fn remove(&mut self, member: &Uuid) -> Result<Outgoing, Rejection> {
+        if !self.is_member(member) {
+            return Err(Rejection::NotInRoom);
+        }
+        self.leave(member);
+        Ok(Outgoing {
+            conn: *member,
+            reply: Reply::Event(Event::Kicked { room: self.id }),
+            delay: None,
+        })
+    }
+
+    /// Bans or unbans a connection and its name on behalf of the host.
+    /// Returns the notice for the member if it was in the room.
+    pub fn ban(
+        &mut self,
+        conn: &Uuid,
+        member: Uuid,
+        name: Option<String>,
+        banned: bool,
+    ) -> Result<Option<Outgoing>, Rejection> {
+        if &self.host != conn || &member == conn {
+            return Err(Rejection::NotHost);
+        }
+        if !banned {
+            self.banned.remove(&member);
+            if let Some(name) = name {
+                self.banned_names.remove(&name);
+            }
+            return Ok(None);
+        }
+        self.banned.insert(member);
+        self.banned_names.extend(name);
+        if self.is_member(&member) {
+            self.kick(conn, &member).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Everything about the room, for admins
+    pub fn details(&self) -> RoomDetails {
+        RoomDetails {
+            info: self.info(),
+            host: self.host,
+            players: self.players.clone(),
+            spectators: self.spectators.keys().copied().collect(),
+            version: self.version,
+            view: self
+                .instance
+                .as_ref()
+                .and_then(|instance| instance.view(Viewer::Omniscient).ok()),
+        }
+    }
+
+    /// Kicks every member and stops the game, the pending timer and bots find nothing to do
+    fn close(&mut self) -> Vec<Outgoing> {
+        let notices = self.to_members(Reply::Event(Event::Kicked { room: self.id }));
+        self.players.clear();
+        self.bots.clear();
+        self.spectators.clear();
+        self.instance = None;
+        self.timer = None;
+        self.undo = None;
+        self.version += 1;
+        notices
+    }
+
+    fn is_live(&self) -> bool {
+        self.instance.is_some() && !self.finished
+    }
+
+    /// `None` if there is no game in progress
+    fn snapshot(&self, names: &HashMap<Uuid, String>) -> Option<RoomSnapshot> {
+        if !self.is_live() {
+            return None;
+        }
+        let view = match self.instance.as_ref()?.view(Viewer::Omniscient) {
+            Ok(view) => view,
+            Err(e) => {
+                warn!("Unable to take a snapshot of room {}: {}", self.id, e);
+                return None;
+            }
+        };
+        Some(RoomSnapshot {
+            id: self.id,
+            game: self.game.name().clone(),
+            version: self.game.version().clone(),
+            settings: self.settings.clone(),
+            players: self
+                .players
+                .iter()
+                .map(|seat| seat.player().and_then(|conn| names.get(conn).cloned()))
+                .collect(),
+            view,
+            taken: SystemTime::now(),
+        })
+    }
+
+    /// The part of the state each member is allowed to see, as the changes to what they saw last when possible
+    pub fn broadcast(&mut self) -> Vec<Outgoing> {
+        if self.instance.is_none() {
+            return Vec::new();
+        }
+        let seated = self.seated;
+        let seats = self.players.iter().enumerate().filter_map(|(seat, conn)| {
+            let viewer = if seat < seated {
+                Viewer::Seat(seat)
+            } else {
+                Viewer::Spectator
+            };
+            conn.player().map(|conn| (*conn, viewer))
+        });
+        let spectators = self.spectators.iter().map(|(conn, omniscient)| {
+            if *omniscient {
+                (*conn, Viewer::Omniscient)
+            } else {
+                (*conn, Viewer::Spectator)
+            }
+        });
+        let members: Vec<_> = seats.chain(spectators).collect();
+        // Those who left start over with the whole state if they come back
+        self.sent
+            .retain(|conn, _| members.iter().any(|(member, _)| member == conn));
+        members
+            .into_iter()
+            .filter_map(|(conn, viewer)| self.outgoing(conn, viewer, false))
+            .collect()
+    }
+
+    fn viewer(&self, conn: &Uuid) -> Option<Viewer> {
+        match (self.seat(conn), self.spectators.get(conn)) {
+            (Some(seat), _) if seat < self.seated => Some(Viewer::Seat(seat)),
+            (Some(_), _) => Some(Viewer::Spectator),
+            (None, Some(true)) => Some(Viewer::Omniscient),
+            (None, Some(false)) => Some(Viewer::Spectator),
+            (None, None) => None,
+        }
+    }
+
+    /// The whole current state for one member, used when joining a started game
+    pub fn state_for(&mut self, conn: &Uuid) -> Vec<Outgoing> {
+        match self.viewer(conn) {
+            Some(viewer) => self.outgoing(*conn, viewer, true).into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The whole current state again, for a member that missed a delta
+    pub fn resync(&mut self, conn: &Uuid) -> Result<Vec<Outgoing>, Rejection> {
+        if !self.is_member(conn) {
+            return Err(Rejection::NotInRoom);
+        }
+        if self.instance.is_none() {
+            return Err(Rejection::NotStarted);
+        }
+        Ok(self.state_for(conn))
+    }
+
+    fn outgoing(&mut self, conn: Uuid, viewer: Viewer, whole: bool) -> Option<Outgoing> {
+        let view = match self.instance.as_ref()?.view(viewer) {
+            Ok(view) => view,
+            Err(e) => {
+                warn!("Unable to project the state for {:?}: {}", viewer, e);
+                return None;
+            }
+        };
+        let (seq, changes) = match self.sent.get(&conn) {
+            Some(sent) if !whole => (sent.seq + 1, sent.view.diff(&view)),
+            Some(sent) => (sent.seq + 1, None),
+            None => (0, None),
+        };
+        let event = match changes {
+            Some(changes) if changes.is_empty() => return None,
+            Some(changes) => Event::StateDelta {
+                room: self.id,
+                seq,
+                changes,
+            },
+            None => Event::State {
+                room: self.id,
+                seq,
+                view: view.clone(),
+            },
+        };
+        self.sent.insert(conn, SentView { seq, view });
+        // Omniscient views are delayed so that spectators can't relay hidden cards to the players
+        let delay = match viewer {
+            Viewer::Omniscient => self
+                .settings
+                .omniscient_delay
+                .map(|x| (Duration::from_secs(x), self.delayed.clone())),
+            _ => None,
+        };
+        Some(Outgoing {
+            conn,
+            reply: Reply::Event(event),
+            delay,
+        })
+    }
+}
+
+/// A message for a room member, built while the room is locked.
+/// States have to be queued before the lock is released, so that they are sent in the order they were numbered.
+pub struct Outgoing {
+    conn: Uuid,
+    reply: Reply,
+    delay: Option<(Duration, DelayLine)>,
+}
+
+/// The delayed messages of a room, sent one after the other by a single task so that they keep their order
+#[derive(Clone, Default)]
+pub struct DelayLine(Arc<std::sync::Mutex<Delayed>>);
+
+#[derive(Default)]
+struct Delayed {
+    queue: VecDeque<(Instant, Uuid, Reply)>,
+    /// Whether a task is sending them
+    running: bool,
+}
+
+impl DelayLine {
+    fn push(&self, server: &proto::ServerProtocol, due: Instant, conn: Uuid, reply: Reply) {
+        let mut delayed = self.0.lock().unwrap();
+        delayed.queue.push_back((due, conn, reply));
+        if !delayed.running {
+            delayed.running = true;
+            smol::spawn(self.clone().run(server.clone())).detach();
+        }
+    }
+
+    async fn run(self, server: proto::ServerProtocol) {
+        loop {
+            let next = {
+                let mut delayed = self.0.lock().unwrap();
+                let next = delayed.queue.pop_front();
+                delayed.running = next.is_some();
+                next
+            };
+            let (due, conn, reply) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            smol::Timer::at(due).await;
+            server.send(&conn, &reply).await.ok();
+        }
+    }
+}
+
+#[instrument(skip(server, outgoing))]
+pub async fn deliver(server: &proto::ServerProtocol, outgoing: Vec<Outgoing>) {
+    for Outgoing { conn, reply, delay } in outgoing {
+        match delay {
+            Some((delay, line)) => line.push(server, Instant::now() + delay, conn, reply),
+            None => {
+                if let Err(e) = server.send(&conn, &reply).await {
+                    warn!("Unable to send to {}: {}", conn, e);
+                }
+            }
+        }
+    }
+}
+
+/// Sends the new state to the members and restarts the timer if someone else has to act now.
+/// Ends the game and records its result if it is over.
+pub async fn changed(server: &proto::ServerProtocol, room: &Arc<Mutex<Room>>) {
+    let (timer, bot, result) = {
+        let mut room = room.lock().await;
+        room.version += 1;
+        let mut outgoing = room.drop_undo();
+        outgoing.extend(room.broadcast());
+        let result = room.finish().map(|(notices, result)| {
+            outgoing.extend(notices);
+            (room.stats.clone(), room.reports.clone(), result)
+        });
+        let bot = room.waiting_bot().map(|_| room.version);
+        let timer = room.restart_timer();
+        // Still locked, so that the states of changes made at the same time go out in order
+        deliver(server, outgoing).await;
+        (timer, bot, result)
+    };
+    if let Some((stats, reports, result)) = result {
+        stats.record(result.clone()).await;
+        reports.send(result).await.ok();
+    }
+    if let Some((generation, limit, notices)) = timer {
+        deliver(server, notices).await;
+        schedule_timeout(server.clone(), room.clone(), generation, limit);
+    }
+    if let Some(version) = bot {
+        schedule_bot(server.clone(), room.clone(), version);
+    }
+}
+
+fn new_invite() -> String {
+    let mut rng = thread_rng();
+    (0..INVITE_LENGTH)
+        .map(|_| *INVITE_CHARACTERS.choose(&mut rng).unwrap() as char)
+        .collect()
+}
+
+fn schedule_bot(server: proto::ServerProtocol, room: Arc<Mutex<Room>>, version: u64) {
+    let task: Pin<Box<dyn Future<Output = ()> + Send>> = Box::pin(async move {
+        smol::Timer::after(BOT_DELAY).await;
+        let acted = room.lock().await.bot_act(version);
+        if acted {
+            changed(&server, &room).await;
+        }
+    });
+    smol::spawn(task).detach();
+}
+
+fn schedule_timeout(
+    server: proto::ServerProtocol,
+    room: Arc<Mutex<Room>>,
+    generation: u64,
+    limit: Duration,
+) {
+    // Boxed because the task ends up scheduling the next timeout
+    let task: Pin<Box<dyn Future<Output = ()> + Send>> = Box::pin(async move {
+        smol::Timer::after(limit).await;
+        let expired = room.lock().await.timeout(generation);
+        if expired {
+            changed(&server, &room).await;
+        }
+    });
+    smol::spawn(task).detach();
+}
+
+/// All the rooms in the server
+#[derive(Clone)]
+pub struct Rooms {
+    rooms: Arc<RwLock<HashMap<Uuid, Arc<Mutex<Room>>>>>,
+    /// The rooms that aren't public by invite code
+    invites: Arc<RwLock<HashMap<String, Uuid>>>,
+    /// Where the rooms record their results
+    stats: Stats,
+    reports: Sender<MatchResult>,
+    /// Rooms open at the same time, unlimited if `None`
+    max_rooms: Option<usize>,
+    metrics: Arc<Metrics>,
+}
+
+impl Rooms {
+    pub fn new(
+        stats: Stats,
+        reports: Sender<MatchResult>,
+        max_rooms: Option<usize>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            rooms: Default::default(),
+            invites: Default::default(),
+            stats,
+            reports,
+            max_rooms,
+            metrics,
+        }
+    }
+
+    /// Creates a room with the host in the first seat, once its options are checked against the game.
+    /// Returns its id and its invite code if it isn't public.
+    pub async fn create(
+        &self,
+        game: ThreadSafeGame,
+        host: Uuid,
+        mut settings: RoomSettings,
+    ) -> Result<(Uuid, Option<String>), Rejection> {
+        settings.options = game
+            .manifest()
+            .resolve_options(&settings.options)
+            .map_err(Rejection::InvalidOption)?;
+        let mut rooms = self.rooms.write().await;
+        if self.max_rooms.is_some_and(|max| rooms.len() >= max) {
+            return Err(Rejection::TooManyRooms);
+        }
+        let mut id = Uuid::new_v4();
+        while rooms.contains_key(&id) {
+            id = Uuid::new_v4();
+        }
+        let invite = match settings.visibility {
+            Visibility::Public => None,
+            // For password rooms the code replaces the password, whoever has it gets in without one
+            _ => {
+                let mut invites = self.invites.write().await;
+                let mut code = new_invite();
+                while invites.contains_key(&code) {
+                    code = new_invite();
+                }
+                invites.insert(code.clone(), id);
+                Some(code)
+            }
+        };
+        let room = Room {
+            id,
+            game,
+            host,
+            settings,
+            players: vec![Seat::Player(host)],
+            bots: HashMap::new(),
+            spectators: HashMap::new(),
+            muted: HashSet::new(),
+            banned: HashSet::new(),
+            banned_names: HashSet::new(),
+            invite: invite.clone(),
+            instance: None,
+            finished: false,
+            seated: 0,
+            timer: None,
+            timer_generation: 0,
+            version: 0,
+            sent: HashMap::new(),
+            delayed: DelayLine::default(),
+            undo: None,
+            stats: self.stats.clone(),
+            reports: self.reports.clone(),
+            metrics: self.metrics.clone(),
+        };
+        rooms.insert(id, Arc::new(Mutex::new(room)));
+        Ok((id, invite))
+    }
+
+    /// The room an invite code is for, codes aren't case sensitive
+    pub async fn invite(&self, code: &str) -> Option<Uuid> {
+        self.invites
+            .read()
+            .await
+            .get(&code.trim().to_uppercase())
+            .copied()
+    }
+
+    /// Creates and starts a room with the players in order, the first one hosts it
+    pub async fn create_match(
+        &self,
+        game: ThreadSafeGame,
+        players: &[Uuid],
+    ) -> Result<(Uuid, Arc<Mutex<Room>>), Rejection> {
+        let (id, _) = self
+            .create(game, players[0], RoomSettings::default())
+            .await?;
+        let room = self.get(&id).await.ok_or(Rejection::NoSuchRoom)?;
+        let res = {
+            let mut r = room.lock().await;
+            players[1..]
+                .iter()
+                .try_for_each(|conn| r.join(*conn).map(|_| ()))
+                .and_then(|()| r.start(&players[0]))
+        };
+        match res {
+            Ok(()) => Ok((id, room)),
+            Err(e) => {
+                self.rooms.write().await.remove(&id);
+                Err(e)
+            }
+        }
+    }
+
+    pub async fn get(&self, id: &Uuid) -> Option<Arc<Mutex<Room>>> {
+        self.rooms.read().await.get(id).cloned()
+    }
+
+    /// The rooms the connection is allowed to see
+    pub async fn list(&self, conn: &Uuid) -> Vec<RoomInfo> {
+        let rooms: Vec<_> = self.rooms.read().await.values().cloned().collect();
+        let mut infos = Vec::with_capacity(rooms.len());
+        for room in rooms {
+            let room = room.lock().await;
+            if room.visible_to(conn) {
+                infos.push(room.info());
+            }
+        }
+        infos
+    }
+
+    /// Removes the connection from a room, and the room itself if nobody is left
+    pub async fn leave(&self, id: &Uuid, conn: &Uuid) -> Result<(), Rejection> {
+        let room = self.get(id).await.ok_or(Rejection::NoSuchRoom)?;
+        let mut room = room.lock().await;
+        if !room.is_member(conn) {
+            return Err(Rejection::NotInRoom);
+        }
+        if room.leave(conn) {
+            self.forget(&mut room).await;
+        }
+        Ok(())
+    }
+
+    /// Removes a member on behalf of an admin, and the room itself if nobody is left.
+    /// Returns the notice for the kicked member
+    pub async fn remove(&self, id: &Uuid, member: &Uuid) -> Result<Outgoing, Rejection> {
+        let room = self.get(id).await.ok_or(Rejection::NoSuchRoom)?;
+        let mut room = room.lock().await;
+        let notice = room.remove(member)?;
+        if room.is_empty() {
+            self.forget(&mut room).await;
+        }
+        Ok(notice)
+    }
+
+    /// Drops an empty room along with its invite code
+    async fn forget(&self, room: &mut MutexGuard<'_, Room>) {
+        self.rooms.write().await.remove(&room.id);
+        if let Some(code) = &room.invite {
+            self.invites.write().await.remove(code);
+        }
+        self.report_abandoned(room.abandoned()).await;
+    }
+
+    /// Removes a disconnected connection from every room it was in
+    pub async fn leave_all(&self, conn: &Uuid) {
+        let rooms: Vec<_> = self.rooms.read().await.values().cloned().collect();
+        for room in rooms {
+            let id = room.lock().await.id();
+            self.leave(&id, conn).await.ok();
+        }
+    }
+
+    /// Every room, for admins
+    pub async fn all(&self) -> Vec<RoomInfo> {
+        let rooms: Vec<_> = self.rooms.read().await.values().cloned().collect();
+        let mut infos = Vec::with_capacity(rooms.len());
+        for room in rooms {
+            infos.push(room.lock().await.info());
+        }
+        infos
+    }
+
+    /// The rooms the connection is a member of
+    pub async fn member_of(&self, conn: &Uuid) -> Vec<Uuid> {
+        let rooms: Vec<_> = self.rooms.read().await.values().cloned().collect();
+        let mut ids = Vec::new();
+        for room in rooms {
+            let room = room.lock().await;
+            if room.is_member(conn) {
+                ids.push(room.id);
+            }
+        }
+        ids
+    }
+
+    /// Removes the room, returns the notices for its members.
+    /// A game in progress is given up, so a tournament match in it is forfeited
+    pub async fn close(&self, id: &Uuid) -> Result<Vec<Outgoing>, Rejection> {
+        let room = self
+            .rooms
+            .write()
+            .await
+            .remove(id)
+            .ok_or(Rejection::NoSuchRoom)?;
+        let mut room = room.lock().await;
+        if let Some(code) = &room.invite {
+            self.invites.write().await.remove(code);
+        }
+        self.report_abandoned(room.abandoned()).await;
+        Ok(room.close())
+    }
+
+    /// Tells the tournaments about a removed room whose game wasn't over, so that its match doesn't wait forever
+    async fn report_abandoned(&self, result: Option<MatchResult>) {
+        if let Some(result) = result {
+            self.reports.send(result).await.ok();
+        }
+    }
+
+    /// Rooms with a game in progress
+    pub async fn live(&self) -> usize {
+        let rooms: Vec<_> = self.rooms.read().await.values().cloned().collect();
+        let mut live = 0;
+        for room in rooms {
+            if room.lock().await.is_live() {
+                live += 1;
+            }
+        }
+        live
+    }
+
+    /// Writes every room with a game in progress to the folder, returns how many there were
+    pub async fn snapshot<P: AsRef<Path>>(
+        &self,
+        folder: P,
+        players: &Players,
+    ) -> io::Result<usize> {
+        let folder = folder.as_ref();
+        fs::create_dir_all(folder)?;
+        let names = players.names().await;
+        let rooms: Vec<_> = self.rooms.read().await.values().cloned().collect();
+        let mut count = 0;
+        for room in rooms {
+            let snapshot = match room.lock().await.snapshot(&names) {
+                Some(x) => x,
+                None => continue,
+            };
+            let path = folder.join(snapshot.id.to_string());
+            let partial = path.with_extension("partial");
+            bincode::serialize_into(File::create(&partial)?, &snapshot)
+                .map_err(io::Error::other)?;
+            fs::rename(&partial, &path)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// A room with a game in progress as it was when the server shut down
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RoomSnapshot {
+    pub id: Uuid,
+    pub game: String,
+    pub version: String,
+    pub settings: RoomSettings,
+    /// The names of the players by seat, `None` for bots, empty seats and unnamed players
+    pub players: Vec<Option<String>>,
+    /// Everything on the table, hidden cards included
+    pub view: GameView,
+    pub taken: SystemTime,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::tests::game;
+    use smol::net::{TcpListener, TcpStream};
+
+    /// Every click adds a card to the only pile
+    const COUNTER_GAME: &str = r#"
+        name = "COUNTER"
+        version = "1.0.0"
+        players = {2, 2}
+
+        function setup(players)
+            local counter = {face_down = false, cards = {}}
+            function counter:on_click(player_piles)
+                table.insert(self.cards, {image = "card.png"})
+                return player_piles
+            end
+            return {counter}, {}
+        end
+    "#;
+
+    #[test]
+    fn states_of_concurrent_changes_arrive_in_order() {
+        const CLICKS: usize = 40;
+        let folder = std::env::temp_dir().join(format!("cards-{}", Uuid::new_v4()));
+        let (server, room, mut clients) = smol::block_on(async {
+            let stats = Stats::open(&folder, Players::new(HashSet::new())).unwrap();
+            let (reports, _) = smol::channel::unbounded();
+            let rooms = Rooms::new(stats, reports, None, Arc::new(Metrics::default()));
+            let server = proto::ServerProtocol::new();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut clients = Vec::new();
+            let mut conns = Vec::new();
+            for _ in 0..2 {
+                let client = TcpStream::connect(listener.local_addr().unwrap())
+                    .await
+                    .unwrap();
+                let (tcp, _) = listener.accept().await.unwrap();
+                conns.push(server.connection(tcp).await.unwrap());
+                clients.push((proto::ClientProtocolStream::new(client), conns.len() - 1));
+            }
+            let (_, room) = rooms
+                .create_match(game(COUNTER_GAME), &conns)
+                .await
+                .unwrap();
+            changed(&server, &room).await;
+            let clients: Vec<_> = clients
+                .into_iter()
+                .map(|(client, i)| (client, conns[i]))
+                .collect();
+            (server, room, clients)
+        });
+        // On threads of their own, so that they really run at the same time
+        let clicks: Vec<_> = (0..CLICKS)
+            .map(|i| {
+                let (server, room, conn) = (server.clone(), room.clone(), clients[i % 2].1);
+                std::thread::spawn(move || {
+                    smol::block_on(async {
+                        room.lock().await.click(&conn, PileId::Common(0)).unwrap();
+                        changed(&server, &room).await;
+                    })
+                })
+            })
+            .collect();
+        for click in clicks {
+            click.join().unwrap();
+        }
+        smol::block_on(async {
+            for (client, _) in &mut clients {
+                // A change that finds the view as it was sent doesn't send anything
+                let mut view: Option<GameView> = None;
+                let mut next = 0;
+                while view
+                    .as_ref()
+                    .is_none_or(|view| view.piles[0].cards.len() < CLICKS)
+                {
+                    match client.recv().await.unwrap() {
+                        Reply::Event(Event::State {
+                            seq, view: state, ..
+                        }) => {
+                            assert_eq!(seq, next);
+                            view = Some(state);
+                        }
+                        Reply::Event(Event::StateDelta { seq, changes, .. }) => {
+                            assert_eq!(seq, next);
+                            assert!(view.as_mut().unwrap().apply(&changes));
+                        }
+                        _ => continue,
+                    }
+                    next += 1;
+                }
+            }
+        });
+        fs::remove_dir_all(&folder).ok();
+    }
+
+    #[test]
+    fn players_join_a_finished_game_for_the_rematch() {
+        // The first click wins the game
+        const GAME: &str = r#"
+            name = "FIRST"
+            version = "1.0.0"
+            players = {2, 3}
+
+            function setup(players)
+                local pile = {face_down = false, cards = {}}
+                function pile:on_click(player_piles)
+                    table.insert(self.cards, {image = "card.png"})
+                    return player_piles
+                end
+                return {pile}, {}
+            end
+
+            function check_winner(state)
+                if #state.piles[1].cards > 0 then
+                    return 1
+                end
+            end
+        "#;
+        let folder = std::env::temp_dir().join(format!("cards-{}", Uuid::new_v4()));
+        smol::block_on(async {
+            let stats = Stats::open(&folder, Players::new(HashSet::new())).unwrap();
+            let (reports, _) = smol::channel::unbounded();
+            let rooms = Rooms::new(stats, reports, None, Arc::new(Metrics::default()));
+            let (host, other, late) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+            let (_, room) = rooms
+                .create_match(game(GAME), &[host, other])
+                .await
+                .unwrap();
+            let mut room = room.lock().await;
+            assert!(matches!(room.join(late), Err(Rejection::AlreadyStarted)));
+            room.click(&host, PileId::Common(0)).unwrap();
+            assert!(room.finish().is_some());
+            assert!(matches!(room.join(late), Ok(2)));
+            // The finished game was set up without the new seat
+            assert_eq!(room.viewer(&late), Some(Viewer::Spectator));
+            assert_eq!(room.state_for(&late).len(), 1);
+            room.start(&host).unwrap();
+            assert_eq!(room.viewer(&late), Some(Viewer::Seat(2)));
+            assert!(matches!(
+                room.join(Uuid::new_v4()),
+                Err(Rejection::AlreadyStarted)
+            ));
+        });
+        fs::remove_dir_all(&folder).ok();
+    }
+}
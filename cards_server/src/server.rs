@@ -1,72 +1,877 @@
-use crate::game::{Game, ThreadSafeGame};
+use crate::auth::{Auth, Identity};
+use crate::chat::{self, AddressLimiter, RateLimiter};
+use crate::config::{tracing_level, Config, Features};
+use crate::game::{Game, Games, ThreadSafeGame};
+use crate::metrics::{self, Metrics};
+use crate::players::Players;
+use crate::queue::{Queue, QueueKey};
+use crate::room::{changed, deliver, Outgoing, Room, Rooms};
+use crate::stats::Stats;
+use crate::tournament::Tournaments;
 
 use cards_protocol as proto;
-use smol::{net, prelude::*};
+use cards_subscriber::{ApplyTo, Filter, Subscriber, TargetKind};
+use proto::{
+    AdminReply, AdminRequest, ConnectionInfo, DisconnectReason, Event, Message, Rejection, Reply,
+    Request,
+};
+use smol::{channel::Receiver, lock::Mutex, net, prelude::*};
+use std::{
+    convert::TryFrom,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
-use tracing::{info, instrument, warn, error, debug};
+use tracing::{info, instrument, warn};
 
-pub fn run(games: Vec<Game>) -> ! {
-    smol::block_on(web_server(games))
+/// Failed handshakes before the connection is closed, so passwords can't be tried one after the other
+const MAX_FAILED_HELLOS: u32 = 3;
+/// Wrong invite codes an address can try in a row
+const INVITE_BURST: u32 = 5;
+/// Time it takes to be able to try one more invite code after that
+const INVITE_REFILL: Duration = Duration::from_secs(10);
+
+/// Serves on an already bound listener until SIGINT or SIGTERM, without TLS the connections are plaintext.
+/// The metrics are served on their own listener when there's one.
+/// Returns once every connection is closed, or with what kept the server from starting.
+pub fn run(
+    listener: std::net::TcpListener,
+    metrics: Option<std::net::TcpListener>,
+    config: Config,
+    games: Vec<Game>,
+    auth: Auth,
+    tls: Option<proto::ServerTls>,
+    log: Subscriber,
+) -> Result<(), String> {
+    let (signal, signals) = smol::channel::unbounded();
+    ctrlc::set_handler(move || {
+        signal.try_send(()).ok();
+    })
+    .expect("Unable to handle the termination signals");
+    smol::block_on(web_server(
+        listener, metrics, config, games, auth, tls, log, signals,
+    ))
 }
 
-#[instrument(skip(games))]
-async fn web_server(games: Vec<Game>) -> ! {
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(listener, metrics_listener, config, games, auth, tls, log, signals))]
+async fn web_server(
+    listener: std::net::TcpListener,
+    metrics_listener: Option<std::net::TcpListener>,
+    config: Config,
+    games: Vec<Game>,
+    auth: Auth,
+    tls: Option<proto::ServerTls>,
+    log: Subscriber,
+    signals: Receiver<()>,
+) -> Result<(), String> {
     // let span = span!(Level::INFO, "web server");
     // let _enter = span.enter();
-    let games: Vec<ThreadSafeGame> = games.iter().map(|x| x.thread_safe()).collect();
-    let listener = match net::TcpListener::bind("0.0.0.0:25566").await {
-        Ok(x) => x,
-        Err(_) => net::TcpListener::bind("0.0.0.0:0").await.unwrap(),
-    };
-    info!(
-        "Listening on port {}",
-        listener.local_addr().unwrap().port()
+    let games = Games::new(games.iter().map(|x| x.thread_safe()).collect());
+    let listener = net::TcpListener::try_from(listener).unwrap();
+    info!("Listening on {}", listener.local_addr().unwrap());
+    let metrics = Arc::new(Metrics::default());
+    let server = match tls {
+        Some(tls) => proto::ServerProtocol::with_tls(tls),
+        None => proto::ServerProtocol::new(),
+    }
+    .observed_by(metrics.clone())
+    .with_timeouts(config.heartbeat.timeouts())
+    .with_max_frame(config.limits.max_frame)
+    .with_queue_limit(
+        config.limits.max_queue,
+        Duration::from_secs(config.limits.queue_grace),
+    );
+    let players = Players::new(auth.reserved().clone());
+    let stats = Stats::open(&config.stats, players.clone())
+        .map_err(|e| format!("Unable to load the stats: {}", e))?;
+    let (reports, results) = smol::channel::unbounded();
+    let rooms = Rooms::new(
+        stats.clone(),
+        reports,
+        config.limits.max_rooms,
+        metrics.clone(),
     );
+    let tournaments = Tournaments::open(
+        &config.tournaments,
+        games.clone(),
+        players.clone(),
+        rooms.clone(),
+        stats.clone(),
+    )
+    .map_err(|e| format!("Unable to load the tournaments: {}", e))?;
+    {
+        let server = server.clone();
+        let tournaments = tournaments.clone();
+        smol::spawn(async move {
+            while let Ok(result) = results.recv().await {
+                tournaments.report(&server, result).await;
+            }
+        })
+        .detach();
+    }
+    // Stops with the server when the task is dropped
+    let _metrics = metrics_listener.map(|listener| {
+        let listener = net::TcpListener::try_from(listener).unwrap();
+        let (server, rooms, games) = (server.clone(), rooms.clone(), games.clone());
+        smol::spawn(metrics::serve(listener, move || {
+            let (metrics, server, rooms, games) = (
+                metrics.clone(),
+                server.clone(),
+                rooms.clone(),
+                games.clone(),
+            );
+            async move { metrics.render(&server, &rooms, &games).await }
+        }))
+    });
+    let context = Context {
+        games,
+        rooms,
+        players,
+        stats,
+        queue: Queue::default(),
+        tournaments,
+        auth: Arc::new(auth),
+        features: config.features,
+        shutting_down: Default::default(),
+        log,
+        games_folder: config.games.clone(),
+        invite_limiter: AddressLimiter::new(INVITE_BURST, INVITE_REFILL),
+    };
+    let connections = Arc::new(AtomicUsize::new(0));
     let mut incoming = listener.incoming();
-    let server = proto::ServerProtocol::new();
-    while let Some(stream) = incoming.next().await {
-        let uuid = server.connection(stream.unwrap()).await;
-        smol::spawn(handle_connection(server.clone(), uuid, games.clone())).detach();
+    loop {
+        let stream = incoming
+            .next()
+            .or(async {
+                signals.recv().await.ok();
+                None
+            })
+            .await;
+        let stream = match stream {
+            Some(stream) => stream.unwrap(),
+            None => break,
+        };
+        let open = connections.fetch_add(1, Ordering::SeqCst);
+        if config.limits.max_connections.is_some_and(|max| open >= max) {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            warn!(
+                "Refusing {:?}, too many connections",
+                stream.peer_addr().ok()
+            );
+            continue;
+        }
+        let server = server.clone();
+        let context = context.clone();
+        let connections = connections.clone();
+        // The TLS handshake happens here, so slow peers don't hold up the others
+        smol::spawn(async move {
+            match server.connection(stream).await {
+                Ok(uuid) => handle_connection(server, uuid, context).await,
+                Err(e) => info!("Handshake failed: {:?}", e),
+            }
+            connections.fetch_sub(1, Ordering::SeqCst);
+        })
+        .detach();
     }
-    unreachable!()
+    drop(incoming);
+    drop(listener);
+    shutdown(&server, &context, &config, &signals).await;
+    Ok(())
 }
 
-#[instrument(skip(server, games))]
-async fn handle_connection(
-    server: proto::ServerProtocol,
-    uuid: proto::Uuid,
-    games: Vec<ThreadSafeGame>,
-) -> () {
+/// Lets the games in progress go on until the grace period ends, or a second signal comes,
+/// then keeps the ones left and closes every connection
+async fn shutdown(
+    server: &proto::ServerProtocol,
+    context: &Context,
+    config: &Config,
+    signals: &Receiver<()>,
+) {
+    context.shutting_down.store(true, Ordering::SeqCst);
+    let grace = Duration::from_secs(config.shutdown_grace);
+    let deadline = SystemTime::now() + grace;
+    info!("Shutting down, waiting up to {:?} for the games", grace);
+    let event = Reply::Event(Event::ServerShuttingDown { deadline });
+    // Only queued, so a slow connection doesn't push the deadline back
+    for conn in server.connections().await {
+        server.send(&conn, &event).await.ok();
+    }
+
+    let drained = async {
+        while context.rooms.live().await > 0 {
+            smol::Timer::after(Duration::from_secs(1)).await;
+        }
+    };
+    drained
+        .or(async {
+            smol::Timer::after(grace).await;
+        })
+        .or(async {
+            signals.recv().await.ok();
+            info!("Second signal, not waiting for the games");
+        })
+        .await;
+
+    match context
+        .rooms
+        .snapshot(&config.snapshots, &context.players)
+        .await
+    {
+        Ok(0) => (),
+        Ok(count) => info!(
+            "Kept {} games in progress in {}",
+            count,
+            config.snapshots.display()
+        ),
+        Err(e) => warn!("Unable to keep the games in progress: {}", e),
+    }
+    server.disconnect_all(DisconnectReason::ShuttingDown).await;
+    info!("Shut down");
+}
+
+/// What the connections share
+#[derive(Clone)]
+struct Context {
+    games: Games,
+    rooms: Rooms,
+    players: Players,
+    stats: Stats,
+    queue: Queue,
+    tournaments: Tournaments,
+    auth: Arc<Auth>,
+    features: Features,
+    /// Nothing new starts once it's set
+    shutting_down: Arc<AtomicBool>,
+    /// For admins to change the filters
+    log: Subscriber,
+    /// Where the games are reloaded from
+    games_folder: PathBuf,
+    /// Wrong invite codes, by address
+    invite_limiter: AddressLimiter,
+}
+
+#[instrument(skip(server, context))]
+async fn handle_connection(server: proto::ServerProtocol, uuid: proto::Uuid, context: Context) {
     let addr = server.peer_addr(&uuid).await.unwrap();
     // let span = span!(Level::INFO, format!("{} - {}", addr, uuid));
     // let _enter = span.enter();
     info!("Connected to {}", addr);
+    let mut chat_limiter = RateLimiter::default();
+    // Open servers don't need the handshake
+    let mut authenticated = !context.auth.required();
+    let mut hello = false;
+    let mut failed_hellos = 0;
     loop {
         match server.recv(&uuid).await {
-            Ok(req) => {
-                info!("{:?}", req);
-                match req {
-                    proto::Request::Games => server
-                        .send(
-                            &uuid,
-                            &proto::Reply::Games(
-                                games
-                                    .iter()
-                                    .map(|g| (g.name().clone(), g.version().clone()))
-                                    .collect(),
-                            ),
-                        )
-                        .await
-                        .unwrap(),
+            Ok(message) => {
+                match &message {
+                    Message::Hello { .. } => info!("Hello"),
+                    message => info!("{:?}", message),
+                }
+                let reply = match message {
+                    Message::Hello { .. } if hello => {
+                        Reply::Rejected(Rejection::AlreadyAuthenticated)
+                    }
+                    Message::Hello { credentials } => {
+                        match authenticate(&context.auth, credentials).await {
+                            Some((identity, token)) => {
+                                let name = identity.name.clone();
+                                let admin = identity.admin;
+                                match context.players.login(uuid, identity).await {
+                                    Ok(()) => {
+                                        info!("Authenticated as {:?}, admin: {}", name, admin);
+                                        authenticated = true;
+                                        hello = true;
+                                        Reply::Welcome { name, admin, token }
+                                    }
+                                    Err(e) => Reply::Rejected(e),
+                                }
+                            }
+                            None => {
+                                warn!("Failed authentication");
+                                failed_hellos += 1;
+                                Reply::Rejected(Rejection::AuthenticationFailed)
+                            }
+                        }
+                    }
+                    _ if !authenticated => Reply::Rejected(Rejection::Unauthenticated),
+                    Message::Chat { .. } if !context.features.chat => {
+                        Reply::Rejected(Rejection::Disabled)
+                    }
+                    Message::Chat { room, text } => {
+                        chat(&server, uuid, &context.rooms, &mut chat_limiter, room, text).await
+                    }
+                    Message::Request(ref req) if !context.features.allows(req) => {
+                        Reply::Rejected(Rejection::Disabled)
+                    }
+                    Message::Request(
+                        Request::CreateRoom { .. }
+                        | Request::StartGame { .. }
+                        | Request::Enqueue { .. }
+                        | Request::CreateTournament { .. }
+                        | Request::StartTournament { .. },
+                    ) if context.shutting_down.load(Ordering::SeqCst) => {
+                        Reply::Rejected(Rejection::ShuttingDown)
+                    }
+                    Message::Request(req) => {
+                        handle_request(&server, uuid, addr.ip(), &context, req).await
+                    }
+                };
+                if let Err(e) = server.send(&uuid, &reply).await {
+                    info!("{:?}", e)
+                }
+                if failed_hellos >= MAX_FAILED_HELLOS {
+                    warn!(
+                        "Closing the connection after {} failed authentications",
+                        failed_hellos
+                    );
+                    server
+                        .disconnect(&uuid, DisconnectReason::AuthenticationFailed)
+                        .await;
+                    break;
                 }
             }
+            // The frame was read whole, so the connection can go on
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                warn!("Invalid request: {}", e)
+            }
+            // The protocol already closed it
             Err(e) => {
-                if let std::io::ErrorKind::NotConnected = e.kind() {
-                    break;
+                if e.kind() != std::io::ErrorKind::NotConnected {
+                    info!("Dropping the connection: {}", e);
                 }
-                info!("{:?}", e)
+                break;
             }
         }
     }
+    context.rooms.leave_all(&uuid).await;
+    if let Ok(notices) = context.queue.dequeue(&uuid).await {
+        notify(&server, notices).await;
+    }
+    context.players.remove(&uuid).await;
     info!("Disconnected from {}", addr);
 }
+
+/// Off the executor, hashing the password takes a while
+async fn authenticate(
+    auth: &Arc<Auth>,
+    credentials: proto::Credentials,
+) -> Option<(Identity, Option<String>)> {
+    let auth = auth.clone();
+    smol::unblock(move || auth.authenticate(&credentials)).await
+}
+
+async fn handle_request(
+    server: &proto::ServerProtocol,
+    uuid: proto::Uuid,
+    ip: IpAddr,
+    context: &Context,
+    req: Request,
+) -> Reply {
+    let Context {
+        games,
+        rooms,
+        players,
+        stats,
+        queue,
+        tournaments,
+        auth: _,
+        features: _,
+        shutting_down: _,
+        log: _,
+        games_folder: _,
+        invite_limiter,
+    } = context;
+    match req {
+        Request::Games => Reply::Games(
+            games
+                .list()
+                .iter()
+                .map(|g| (g.name().clone(), g.version().clone()))
+                .collect(),
+        ),
+        Request::Rooms => Reply::Rooms(rooms.list(&uuid).await),
+        Request::CreateRoom { game, settings } => match games.find(&game) {
+            Some(game) => match rooms.create(game, uuid, settings).await {
+                Ok((room, invite)) => Reply::RoomCreated { room, invite },
+                Err(e) => Reply::Rejected(e),
+            },
+            None => Reply::Rejected(Rejection::NoSuchGame),
+        },
+        Request::JoinRoom { room, password } => match rooms.get(&room).await {
+            Some(r) => {
+                let name = players.name(&uuid).await;
+                let mut r = r.lock().await;
+                let res = r
+                    .admit(&uuid, name.as_deref(), password.as_deref())
+                    .and_then(|()| r.join(uuid));
+                match res {
+                    Ok(seat) => {
+                        // Nothing before the first game, the finished one until the rematch starts
+                        let state = r.state_for(&uuid);
+                        deliver(server, state).await;
+                        Reply::Joined {
+                            room,
+                            seat: Some(seat),
+                        }
+                    }
+                    Err(e) => Reply::Rejected(e),
+                }
+            }
+            None => Reply::Rejected(Rejection::NoSuchRoom),
+        },
+        Request::JoinInvite { .. } if !invite_limiter.ready(ip) => {
+            Reply::Rejected(Rejection::RateLimited)
+        }
+        Request::JoinInvite { code } => match rooms.invite(&code).await {
+            Some(room) => match rooms.get(&room).await {
+                Some(r) => {
+                    let name = players.name(&uuid).await;
+                    let mut r = r.lock().await;
+                    let res = r
+                        .check_ban(&uuid, name.as_deref())
+                        .and_then(|()| r.join(uuid));
+                    match res {
+                        Ok(seat) => {
+                            let state = r.state_for(&uuid);
+                            deliver(server, state).await;
+                            Reply::Joined {
+                                room,
+                                seat: Some(seat),
+                            }
+                        }
+                        Err(e) => Reply::Rejected(e),
+                    }
+                }
+                None => Reply::Rejected(Rejection::NoSuchRoom),
+            },
+            None => {
+                // Only wrong codes count, so that they can't be guessed
+                invite_limiter.allow(ip);
+                Reply::Rejected(Rejection::NoSuchRoom)
+            }
+        },
+        Request::Spectate {
+            room,
+            omniscient,
+            password,
+        } => match rooms.get(&room).await {
+            Some(r) => {
+                let name = players.name(&uuid).await;
+                let mut r = r.lock().await;
+                let res = r
+                    .admit(&uuid, name.as_deref(), password.as_deref())
+                    .and_then(|()| r.spectate(uuid, omniscient));
+                match res {
+                    Ok(()) => {
+                        // Queued before the room is unlocked, so that no delta overtakes it
+                        let state = r.state_for(&uuid);
+                        deliver(server, state).await;
+                        Reply::Joined { room, seat: None }
+                    }
+                    Err(e) => Reply::Rejected(e),
+                }
+            }
+            None => Reply::Rejected(Rejection::NoSuchRoom),
+        },
+        Request::LeaveRoom { room } => match rooms.leave(&room, &uuid).await {
+            Ok(()) => Reply::Ok,
+            Err(e) => Reply::Rejected(e),
+        },
+        Request::StartGame { room } => match rooms.get(&room).await {
+            Some(r) => {
+                let res = r.lock().await.start(&uuid);
+                match res {
+                    Ok(()) => {
+                        changed(server, &r).await;
+                        Reply::Ok
+                    }
+                    Err(e) => Reply::Rejected(e),
+                }
+            }
+            None => Reply::Rejected(Rejection::NoSuchRoom),
+        },
+        Request::Members { room } => match rooms.get(&room).await {
+            Some(r) => {
+                let r = r.lock().await;
+                if r.is_member(&uuid) {
+                    r.member_list()
+                } else {
+                    Reply::Rejected(Rejection::NotInRoom)
+                }
+            }
+            None => Reply::Rejected(Rejection::NoSuchRoom),
+        },
+        Request::Admin(req) => {
+            if players.is_admin(&uuid).await {
+                info!("Admin request {:?}", req);
+                handle_admin(server, context, req).await
+            } else {
+                Reply::Rejected(Rejection::NotAdmin)
+            }
+        }
+        Request::LegalActions { room } => match rooms.get(&room).await {
+            Some(r) => match r.lock().await.legal_actions(&uuid) {
+                Ok(actions) => Reply::LegalActions { room, actions },
+                Err(e) => Reply::Rejected(e),
+            },
+            None => Reply::Rejected(Rejection::NoSuchRoom),
+        },
+        Request::Resync { room } => match rooms.get(&room).await {
+            Some(r) => {
+                let mut r = r.lock().await;
+                match r.resync(&uuid) {
+                    Ok(state) => {
+                        deliver(server, state).await;
+                        Reply::Ok
+                    }
+                    Err(e) => Reply::Rejected(e),
+                }
+            }
+            None => Reply::Rejected(Rejection::NoSuchRoom),
+        },
+        Request::AddBot { room } => match rooms.get(&room).await {
+            Some(r) => match r.lock().await.add_bot(&uuid) {
+                Ok(()) => Reply::Ok,
+                Err(e) => Reply::Rejected(e),
+            },
+            None => Reply::Rejected(Rejection::NoSuchRoom),
+        },
+        Request::Mute {
+            room,
+            member,
+            muted,
+        } => match rooms.get(&room).await {
+            Some(r) => match r.lock().await.mute(&uuid, member, muted) {
+                Ok(()) => Reply::Ok,
+                Err(e) => Reply::Rejected(e),
+            },
+            None => Reply::Rejected(Rejection::NoSuchRoom),
+        },
+        Request::Kick { room, member } => match rooms.get(&room).await {
+            Some(r) => {
+                let res = r.lock().await.kick(&uuid, &member);
+                match res {
+                    Ok(notice) => {
+                        deliver(server, vec![notice]).await;
+                        Reply::Ok
+                    }
+                    Err(e) => Reply::Rejected(e),
+                }
+            }
+            None => Reply::Rejected(Rejection::NoSuchRoom),
+        },
+        Request::Ban {
+            room,
+            member,
+            banned,
+        } => match rooms.get(&room).await {
+            Some(r) => {
+                let name = players.name(&member).await;
+                let res = r.lock().await.ban(&uuid, member, name, banned);
+                match res {
+                    Ok(notice) => {
+                        deliver(server, notice.into_iter().collect()).await;
+                        Reply::Ok
+                    }
+                    Err(e) => Reply::Rejected(e),
+                }
+            }
+            None => Reply::Rejected(Rejection::NoSuchRoom),
+        },
+        Request::Click { room, pile } => match rooms.get(&room).await {
+            Some(r) => {
+                let res = r.lock().await.click(&uuid, pile);
+                match res {
+                    Ok(()) => {
+                        changed(server, &r).await;
+                        Reply::Ok
+                    }
+                    Err(e) => Reply::Rejected(e),
+                }
+            }
+            None => Reply::Rejected(Rejection::NoSuchRoom),
+        },
+        Request::ProposeUndo { room, whole_turn } => match rooms.get(&room).await {
+            Some(r) => {
+                let res = r.lock().await.propose_undo(&uuid, whole_turn);
+                undo_reply(server, &r, res).await
+            }
+            None => Reply::Rejected(Rejection::NoSuchRoom),
+        },
+        Request::VoteUndo { room, accept } => match rooms.get(&room).await {
+            Some(r) => {
+                let res = r.lock().await.vote_undo(&uuid, accept);
+                undo_reply(server, &r, res).await
+            }
+            None => Reply::Rejected(Rejection::NoSuchRoom),
+        },
+        Request::SetName { name } => match players.set_name(uuid, name).await {
+            Ok(()) => Reply::Ok,
+            Err(e) => Reply::Rejected(e),
+        },
+        Request::Stats { player, game } => Reply::Stats {
+            records: stats.player(&player, &game).await,
+            player,
+            game,
+        },
+        Request::Leaderboard { game } => match games.find(&game) {
+            Some(g) => Reply::Leaderboard {
+                players: stats.leaderboard(&game, g.version()).await,
+                version: g.version().clone(),
+                game,
+            },
+            None => Reply::Rejected(Rejection::NoSuchGame),
+        },
+        Request::Enqueue {
+            game,
+            players: count,
+            balanced,
+        } => match games.find(&game) {
+            Some(g) => {
+                let (min, max) = g.manifest().players;
+                if count < min || count > max {
+                    return Reply::Rejected(Rejection::InvalidPlayerCount);
+                }
+                let rating = stats.rating(&uuid, &game, g.version()).await;
+                let key = QueueKey {
+                    game,
+                    players: count,
+                    balanced,
+                };
+                let (notices, group) = queue.enqueue(uuid, key, rating).await;
+                notify(server, notices).await;
+                if let Some(group) = group {
+                    start_match(server, rooms, g.clone(), group).await;
+                }
+                Reply::Ok
+            }
+            None => Reply::Rejected(Rejection::NoSuchGame),
+        },
+        Request::Dequeue => match queue.dequeue(&uuid).await {
+            Ok(notices) => {
+                notify(server, notices).await;
+                Reply::Ok
+            }
+            Err(e) => Reply::Rejected(e),
+        },
+        Request::CreateTournament { game, format } => {
+            match tournaments.create(&uuid, game, format).await {
+                Ok(id) => Reply::TournamentCreated(id),
+                Err(e) => Reply::Rejected(e),
+            }
+        }
+        Request::JoinTournament { tournament } => {
+            match tournaments.join(&uuid, &tournament).await {
+                Ok(()) => Reply::Ok,
+                Err(e) => Reply::Rejected(e),
+            }
+        }
+        Request::StartTournament { tournament } => {
+            match tournaments.start(server, &uuid, &tournament).await {
+                Ok(()) => Reply::Ok,
+                Err(e) => Reply::Rejected(e),
+            }
+        }
+        Request::Tournament { tournament } => match tournaments.info(&tournament).await {
+            Ok(info) => Reply::Tournament(info),
+            Err(e) => Reply::Rejected(e),
+        },
+        Request::GameOptions { game } => match games.find(&game) {
+            Some(g) => Reply::GameOptions {
+                options: g.manifest().options.clone(),
+                game,
+            },
+            None => Reply::Rejected(Rejection::NoSuchGame),
+        },
+        Request::Answer { room, option } => match rooms.get(&room).await {
+            Some(r) => {
+                let res = r.lock().await.answer(&uuid, option);
+                match res {
+                    Ok(()) => {
+                        changed(server, &r).await;
+                        Reply::Ok
+                    }
+                    Err(e) => Reply::Rejected(e),
+                }
+            }
+            None => Reply::Rejected(Rejection::NoSuchRoom),
+        },
+    }
+}
+
+async fn handle_admin(
+    server: &proto::ServerProtocol,
+    context: &Context,
+    req: AdminRequest,
+) -> Reply {
+    let Context {
+        games,
+        rooms,
+        players,
+        log,
+        games_folder,
+        ..
+    } = context;
+    match req {
+        AdminRequest::Connections => {
+            let mut connections = Vec::new();
+            for id in server.connections().await {
+                connections.push(ConnectionInfo {
+                    id,
+                    address: server.peer_addr(&id).await.ok(),
+                    name: players.name(&id).await,
+                    admin: players.is_admin(&id).await,
+                    rooms: rooms.member_of(&id).await,
+                    latency: server.latency(&id).await,
+                });
+            }
+            Reply::Admin(AdminReply::Connections(connections))
+        }
+        AdminRequest::Rooms => Reply::Admin(AdminReply::Rooms(rooms.all().await)),
+        AdminRequest::InspectRoom { room } => match rooms.get(&room).await {
+            Some(r) => Reply::Admin(AdminReply::Room(Box::new(r.lock().await.details()))),
+            None => Reply::Rejected(Rejection::NoSuchRoom),
+        },
+        AdminRequest::Kick { room, member } => match rooms.remove(&room, &member).await {
+            Ok(notice) => {
+                deliver(server, vec![notice]).await;
+                Reply::Ok
+            }
+            Err(e) => Reply::Rejected(e),
+        },
+        AdminRequest::Disconnect { conn } => {
+            server.disconnect(&conn, DisconnectReason::Admin).await;
+            Reply::Ok
+        }
+        AdminRequest::CloseRoom { room } => match rooms.close(&room).await {
+            Ok(notices) => {
+                deliver(server, notices).await;
+                Reply::Ok
+            }
+            Err(e) => Reply::Rejected(e),
+        },
+        AdminRequest::ReloadGames => {
+            let folder = games_folder.clone();
+            // Loading runs the scripts, off the executor
+            let loaded = smol::unblock(move || crate::try_load_games(folder)).await;
+            match loaded {
+                Ok(loaded) => {
+                    games.replace(loaded.iter().map(|x| x.thread_safe()).collect());
+                    Reply::Admin(AdminReply::GamesReloaded(
+                        loaded
+                            .iter()
+                            .map(|g| (g.name().clone(), g.version().clone()))
+                            .collect(),
+                    ))
+                }
+                Err(e) => {
+                    warn!("Unable to reload the games: {}", e);
+                    Reply::Rejected(Rejection::Game(e))
+                }
+            }
+        }
+        AdminRequest::SetLogFilter { target, level } => {
+            log.set_filter(Filter::new(
+                tracing_level(level),
+                Some(TargetKind::Target(target)),
+                ApplyTo::All,
+            ));
+            Reply::Ok
+        }
+    }
+}
+
+/// Sends replies that don't go through a room
+async fn notify(server: &proto::ServerProtocol, notices: Vec<(proto::Uuid, Reply)>) {
+    for (conn, reply) in notices {
+        if let Err(e) = server.send(&conn, &reply).await {
+            info!("{:?}", e)
+        }
+    }
+}
+
+/// Puts the players matched by the queue in a new room and starts the game
+async fn start_match(
+    server: &proto::ServerProtocol,
+    rooms: &Rooms,
+    game: ThreadSafeGame,
+    players: Vec<proto::Uuid>,
+) {
+    match rooms.create_match(game, &players).await {
+        Ok((id, room)) => {
+            let notices = players
+                .iter()
+                .enumerate()
+                .map(|(seat, conn)| {
+                    (
+                        *conn,
+                        Reply::Event(proto::Event::Matched { room: id, seat }),
+                    )
+                })
+                .collect();
+            notify(server, notices).await;
+            changed(server, &room).await;
+        }
+        Err(e) => {
+            warn!("Unable to start a matched room: {:?}", e);
+            let notices = players
+                .iter()
+                .map(|conn| (*conn, Reply::Rejected(e.clone())))
+                .collect();
+            notify(server, notices).await;
+        }
+    }
+}
+
+async fn undo_reply(
+    server: &proto::ServerProtocol,
+    room: &Arc<Mutex<Room>>,
+    res: Result<(Vec<Outgoing>, bool), Rejection>,
+) -> Reply {
+    match res {
+        Ok((notices, applied)) => {
+            deliver(server, notices).await;
+            if applied {
+                changed(server, room).await;
+            }
+            Reply::Ok
+        }
+        Err(e) => Reply::Rejected(e),
+    }
+}
+
+async fn chat(
+    server: &proto::ServerProtocol,
+    uuid: proto::Uuid,
+    rooms: &Rooms,
+    limiter: &mut RateLimiter,
+    room: proto::Uuid,
+    text: String,
+) -> Reply {
+    let text = text.trim().to_string();
+    if text.is_empty() {
+        return Reply::Rejected(Rejection::EmptyMessage);
+    }
+    if text.chars().count() > chat::MAX_MESSAGE_LENGTH {
+        return Reply::Rejected(Rejection::MessageTooLong);
+    }
+    let room = match rooms.get(&room).await {
+        Some(room) => room,
+        None => return Reply::Rejected(Rejection::NoSuchRoom),
+    };
+    let res = room.lock().await.chat(&uuid, text, limiter);
+    match res {
+        Ok(messages) => {
+            deliver(server, messages).await;
+            Reply::Ok
+        }
+        Err(e) => Reply::Rejected(e),
+    }
+}
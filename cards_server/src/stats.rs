@@ -0,0 +1,256 @@
+use crate::players::Players;
+
+use cards_protocol::{PlayerRecord, Seat, Uuid};
+use serde::{Deserialize, Serialize};
+use smol::lock::Mutex;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fs::{self, OpenOptions},
+    io::{self, BufReader, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use tracing::warn;
+
+/// The rating of a player before their first match
+const INITIAL_RATING: f64 = 1500.;
+/// How many points a match moves the ratings at most
+const K: f64 = 32.;
+/// Players in a leaderboard
+const LEADERBOARD_SIZE: usize = 50;
+
+/// How a finished match went
+#[derive(Debug, Clone)]
+pub struct MatchResult {
+    pub room: Uuid,
+    pub game: String,
+    pub version: String,
+    pub players: Vec<Seat>,
+    /// Seats from first to last
+    pub standings: Vec<usize>,
+    pub scores: Vec<i64>,
+    pub finished: SystemTime,
+}
+
+/// A match as it is kept in the results file, with the names of the players.
+/// Seats without an account, like bots or players that only set a name, aren't rated.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    game: String,
+    version: String,
+    players: Vec<Option<String>>,
+    standings: Vec<usize>,
+    scores: Vec<i64>,
+    finished: SystemTime,
+}
+
+/// Every finished match, stored in a file and summed up per player and game version.
+/// Each match is stored after its length, so that one cut short by a crash can be told apart and dropped
+#[derive(Clone)]
+pub struct Stats {
+    inner: Arc<Mutex<Inner>>,
+    players: Players,
+}
+
+struct Inner {
+    path: PathBuf,
+    /// The records by game name and version, then by player
+    records: HashMap<(String, String), HashMap<String, PlayerRecord>>,
+}
+
+impl Stats {
+    /// Loads the results stored in the folder, a match left half written is cut off the file
+    pub fn open<P: AsRef<Path>>(folder: P, players: Players) -> io::Result<Self> {
+        fs::create_dir_all(&folder)?;
+        let mut inner = Inner {
+            path: folder.as_ref().join("results"),
+            records: HashMap::new(),
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&inner.path)?;
+        let mut reader = BufReader::new(&file);
+        // Where the last whole match ends
+        let mut end = 0;
+        while let Some(entry) = read_entry(&mut reader)? {
+            inner.apply(&entry);
+            end = reader.stream_position()?;
+        }
+        if file.metadata()?.len() > end {
+            warn!(
+                "Dropping a match left half written at the end of {}",
+                inner.path.display()
+            );
+            file.set_len(end)?;
+        }
+        Ok(Self {
+            inner: Arc::new(Mutex::new(inner)),
+            players,
+        })
+    }
+
+    pub async fn record(&self, result: MatchResult) {
+        let mut players = Vec::with_capacity(result.players.len());
+        for seat in &result.players {
+            players.push(match seat {
+                Seat::Player(conn) => self.players.verified_name(conn).await,
+                _ => None,
+            });
+        }
+        let entry = Entry {
+            game: result.game,
+            version: result.version,
+            players,
+            standings: result.standings,
+            scores: result.scores,
+            finished: result.finished,
+        };
+        let mut inner = self.inner.lock().await;
+        let stored = bincode::serialize(&entry).and_then(|bytes| {
+            let mut record = u32::try_from(bytes.len())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                .to_le_bytes()
+                .to_vec();
+            record.extend(bytes);
+            // In a single write, so that a crash leaves at most the last match half written
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&inner.path)
+                .and_then(|mut file| file.write_all(&record))
+                .map_err(bincode::Error::from)
+        });
+        if let Err(e) = stored {
+            warn!(
+                "Unable to store the result of a {} match: {}",
+                entry.game, e
+            );
+        }
+        inner.apply(&entry);
+    }
+
+    /// The rating of a connection in a game version, players that didn't log in have the initial rating
+    pub async fn rating(&self, conn: &Uuid, game: &str, version: &str) -> f64 {
+        match self.players.verified_name(conn).await {
+            Some(name) => self.player_rating(&name, game, version).await,
+            None => INITIAL_RATING,
+        }
+    }
+
+    pub async fn player_rating(&self, player: &str, game: &str, version: &str) -> f64 {
+        let inner = self.inner.lock().await;
+        inner
+            .records
+            .get(&(game.to_string(), version.to_string()))
+            .and_then(|players| players.get(player))
+            .map_or(INITIAL_RATING, |x| x.rating)
+    }
+
+    /// The records of a player in each version of a game
+    pub async fn player(&self, player: &str, game: &str) -> Vec<(String, PlayerRecord)> {
+        let inner = self.inner.lock().await;
+        let mut records: Vec<_> = inner
+            .records
+            .iter()
+            .filter(|((name, _), _)| name == game)
+            .filter_map(|((_, version), players)| {
+                players.get(player).map(|x| (version.clone(), x.clone()))
+            })
+            .collect();
+        records.sort_by(|a, b| a.0.cmp(&b.0));
+        records
+    }
+
+    /// The best rated players of a game version
+    pub async fn leaderboard(&self, game: &str, version: &str) -> Vec<(String, PlayerRecord)> {
+        let inner = self.inner.lock().await;
+        let mut players: Vec<_> = inner
+            .records
+            .get(&(game.to_string(), version.to_string()))
+            .map(|players| {
+                players
+                    .iter()
+                    .map(|(name, record)| (name.clone(), record.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        players.sort_by(|a, b| b.1.rating.partial_cmp(&a.1.rating).unwrap());
+        players.truncate(LEADERBOARD_SIZE);
+        players
+    }
+}
+
+/// The next match in the file, `None` at the end or if it is cut short
+fn read_entry<R: Read>(reader: &mut R) -> io::Result<Option<Entry>> {
+    let mut len = [0; 4];
+    let mut bytes = Vec::new();
+    reader.take(len.len() as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len.len() {
+        return Ok(None);
+    }
+    len.copy_from_slice(&bytes);
+    let len = u32::from_le_bytes(len) as u64;
+    bytes.clear();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Ok(None);
+    }
+    bincode::deserialize(&bytes)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+impl Inner {
+    /// Counts a match into the records.
+    /// The rating is Elo applied to every pair of named players, the higher one in the standings wins the pair.
+    fn apply(&mut self, entry: &Entry) {
+        let records = self
+            .records
+            .entry((entry.game.clone(), entry.version.clone()))
+            .or_default();
+        let ranked: Vec<&String> = entry
+            .standings
+            .iter()
+            .filter_map(|seat| entry.players.get(*seat)?.as_ref())
+            .collect();
+        let before: Vec<f64> = ranked
+            .iter()
+            .map(|name| records.get(*name).map_or(INITIAL_RATING, |x| x.rating))
+            .collect();
+        // Only the first seat wins, even if it was a bot
+        let winner = entry
+            .standings
+            .first()
+            .and_then(|seat| entry.players.get(*seat)?.as_ref());
+        let mut change = vec![0.; ranked.len()];
+        let k = K / (ranked.len().max(2) - 1) as f64;
+        for i in 0..ranked.len() {
+            for j in i + 1..ranked.len() {
+                let expected = 1. / (1. + 10f64.powf((before[j] - before[i]) / 400.));
+                change[i] += k * (1. - expected);
+                change[j] -= k * (1. - expected);
+            }
+        }
+        for (i, name) in ranked.iter().enumerate() {
+            let record = records.entry((*name).clone()).or_insert(PlayerRecord {
+                played: 0,
+                wins: 0,
+                losses: 0,
+                rating: INITIAL_RATING,
+            });
+            record.played += 1;
+            if Some(*name) == winner {
+                record.wins += 1;
+            } else {
+                record.losses += 1;
+            }
+            record.rating = before[i] + change[i];
+        }
+    }
+}
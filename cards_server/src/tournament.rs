@@ -0,0 +1,603 @@
+use crate::game::{Games, ThreadSafeGame};
+use crate::players::Players;
+use crate::room::{changed, Rooms};
+use crate::stats::{MatchResult, Stats};
+
+use cards_protocol as proto;
+use proto::{Event, Pairing, Rejection, Reply, TournamentFormat, TournamentInfo, Uuid};
+use serde::{Deserialize, Serialize};
+use smol::lock::Mutex;
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tracing::warn;
+
+/// Players in each match of a tournament
+const MATCH_SIZE: usize = 2;
+
+/// A tournament as it is stored, players are kept by name so that it survives restarts
+#[derive(Serialize, Deserialize)]
+struct Tournament {
+    id: Uuid,
+    game: String,
+    format: TournamentFormat,
+    admin: String,
+    players: Vec<String>,
+    rounds: Vec<Vec<Match>>,
+    started: bool,
+    finished: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Match {
+    /// Indices into the players of the tournament, in seat order. A match with one player is a bye.
+    players: Vec<usize>,
+    /// Rooms don't survive restarts, so a match that isn't done and has no room still has to be played
+    #[serde(skip)]
+    room: Option<Uuid>,
+    winner: Option<usize>,
+    done: bool,
+}
+
+impl Match {
+    fn new(players: Vec<usize>) -> Self {
+        Self {
+            players,
+            room: None,
+            winner: None,
+            done: false,
+        }
+    }
+
+    fn bye(player: usize) -> Self {
+        Self {
+            players: vec![player],
+            room: None,
+            winner: Some(player),
+            done: true,
+        }
+    }
+}
+
+impl Tournament {
+    /// A point for each match won, byes included
+    fn points(&self) -> Vec<u32> {
+        let mut points = vec![0; self.players.len()];
+        for winner in self.rounds.iter().flatten().filter_map(|m| m.winner) {
+            points[winner] += 1;
+        }
+        points
+    }
+
+    fn standings(&self) -> Vec<(String, u32)> {
+        let mut standings: Vec<_> = self.players.iter().cloned().zip(self.points()).collect();
+        standings.sort_by_key(|(_, points)| Reverse(*points));
+        standings
+    }
+
+    fn met(&self, a: usize, b: usize) -> bool {
+        self.rounds
+            .iter()
+            .flatten()
+            .any(|m| m.players.contains(&a) && m.players.contains(&b))
+    }
+
+    fn round_done(&self) -> bool {
+        self.rounds
+            .last()
+            .is_none_or(|round| round.iter().all(|m| m.done))
+    }
+
+    /// Pairs the players for the next round, or finishes the tournament if it is over.
+    /// The seeding is every player, best rated first.
+    fn next_round(&mut self, seeding: &[usize]) {
+        let round = match self.format {
+            TournamentFormat::Swiss { rounds } if self.rounds.len() < rounds => {
+                Some(self.swiss_round(seeding))
+            }
+            TournamentFormat::Swiss { .. } => None,
+            TournamentFormat::SingleElimination => self.elimination_round(seeding),
+        };
+        match round {
+            Some(round) => self.rounds.push(round),
+            None => self.finished = true,
+        }
+    }
+
+    /// Players are paired in order of points with the closest one they haven't met yet
+    fn swiss_round(&self, seeding: &[usize]) -> Vec<Match> {
+        let points = self.points();
+        let mut order = seeding.to_vec();
+        order.sort_by_key(|x| Reverse(points[*x]));
+        let mut round = Vec::new();
+        if order.len() % 2 == 1 {
+            // The lowest ranked player that hasn't had a bye yet sits this round out
+            let had_bye = |x: usize| self.rounds.iter().flatten().any(|m| m.players == [x]);
+            let i = order
+                .iter()
+                .rposition(|x| !had_bye(*x))
+                .unwrap_or(order.len() - 1);
+            round.push(Match::bye(order.remove(i)));
+        }
+        while !order.is_empty() {
+            let first = order.remove(0);
+            let i = order.iter().position(|x| !self.met(first, *x)).unwrap_or(0);
+            round.push(Match::new(vec![first, order.remove(i)]));
+        }
+        round
+    }
+
+    /// The winners of the last round meet in bracket order, the first round puts the best seeds against the worst
+    fn elimination_round(&self, seeding: &[usize]) -> Option<Vec<Match>> {
+        let alive: Vec<usize> = match self.rounds.last() {
+            None => {
+                // With an odd amount of players the best seed gets a bye
+                let (bye, rest) = seeding.split_at(seeding.len() % 2);
+                let mut round: Vec<Match> = bye.iter().map(|x| Match::bye(*x)).collect();
+                for i in 0..rest.len() / 2 {
+                    round.push(Match::new(vec![rest[i], rest[rest.len() - 1 - i]]));
+                }
+                return Some(round).filter(|_| seeding.len() >= MATCH_SIZE);
+            }
+            Some(round) => round.iter().filter_map(|m| m.winner).collect(),
+        };
+        if alive.len() < MATCH_SIZE {
+            return None;
+        }
+        Some(
+            alive
+                .chunks(MATCH_SIZE)
+                .map(|pair| match pair {
+                    [player] => Match::bye(*player),
+                    pair => Match::new(pair.to_vec()),
+                })
+                .collect(),
+        )
+    }
+
+    fn info(&self) -> TournamentInfo {
+        let name = |x: &usize| self.players[*x].clone();
+        TournamentInfo {
+            id: self.id,
+            game: self.game.clone(),
+            format: self.format,
+            admin: self.admin.clone(),
+            players: self.players.clone(),
+            rounds: self
+                .rounds
+                .iter()
+                .map(|round| {
+                    round
+                        .iter()
+                        .map(|m| Pairing {
+                            players: m.players.iter().map(name).collect(),
+                            room: m.room,
+                            winner: m.winner.as_ref().map(name),
+                            done: m.done,
+                        })
+                        .collect()
+                })
+                .collect(),
+            standings: self.standings(),
+            started: self.started,
+            finished: self.finished,
+        }
+    }
+}
+
+/// Every tournament in the server, each one is stored in its own file in the folder
+#[derive(Clone)]
+pub struct Tournaments {
+    tournaments: Arc<Mutex<HashMap<Uuid, Tournament>>>,
+    folder: PathBuf,
+    games: Games,
+    players: Players,
+    rooms: Rooms,
+    stats: Stats,
+}
+
+impl Tournaments {
+    pub fn open<P: AsRef<Path>>(
+        folder: P,
+        games: Games,
+        players: Players,
+        rooms: Rooms,
+        stats: Stats,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(&folder)?;
+        let mut tournaments = HashMap::new();
+        for entry in fs::read_dir(&folder)? {
+            let path = entry?.path();
+            // Half written files don't have a uuid as their name
+            if path
+                .file_name()
+                .and_then(|x| x.to_str())
+                .and_then(|x| Uuid::parse_str(x).ok())
+                .is_none()
+            {
+                continue;
+            }
+            let tournament: Tournament =
+                bincode::deserialize_from(BufReader::new(File::open(&path)?))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            tournaments.insert(tournament.id, tournament);
+        }
+        Ok(Self {
+            tournaments: Arc::new(Mutex::new(tournaments)),
+            folder: folder.as_ref().to_path_buf(),
+            games,
+            players,
+            rooms,
+            stats,
+        })
+    }
+
+    fn save(&self, tournament: &Tournament) {
+        let path = self.folder.join(tournament.id.to_string());
+        let partial = path.with_extension("partial");
+        let res = File::create(&partial)
+            .map_err(bincode::Error::from)
+            .and_then(|file| bincode::serialize_into(file, tournament))
+            .and_then(|()| fs::rename(&partial, &path).map_err(bincode::Error::from));
+        if let Err(e) = res {
+            warn!("Unable to save tournament {}: {}", tournament.id, e);
+        }
+    }
+
+    fn game(&self, name: &str) -> Result<ThreadSafeGame, Rejection> {
+        self.games.find(name).ok_or(Rejection::NoSuchGame)
+    }
+
+    /// Who the connection takes part as. With accounts only the one it logged in with counts,
+    /// so that nobody gets the seat or the rights of a player that left by taking their name
+    async fn name(&self, conn: &Uuid) -> Result<String, Rejection> {
+        if self.players.has_accounts() {
+            self.players
+                .verified_name(conn)
+                .await
+                .ok_or(Rejection::NotLoggedIn)
+        } else {
+            self.players.name(conn).await.ok_or(Rejection::Unnamed)
+        }
+    }
+
+    /// The connection a player takes part from, the same way `name` tells who it is
+    async fn conn(&self, name: &str) -> Option<Uuid> {
+        if self.players.has_accounts() {
+            self.players.verified_conn(name).await
+        } else {
+            self.players.conn(name).await
+        }
+    }
+
+    pub async fn create(
+        &self,
+        conn: &Uuid,
+        game: String,
+        format: TournamentFormat,
+    ) -> Result<Uuid, Rejection> {
+        let admin = self.name(conn).await?;
+        let (min, max) = self.game(&game)?.manifest().players;
+        if min > MATCH_SIZE || max < MATCH_SIZE {
+            return Err(Rejection::InvalidPlayerCount);
+        }
+        let mut tournaments = self.tournaments.lock().await;
+        let mut id = Uuid::new_v4();
+        while tournaments.contains_key(&id) {
+            id = Uuid::new_v4();
+        }
+        let tournament = Tournament {
+            id,
+            game,
+            format,
+            admin,
+            players: Vec::new(),
+            rounds: Vec::new(),
+            started: false,
+            finished: false,
+        };
+        self.save(&tournament);
+        tournaments.insert(id, tournament);
+        Ok(id)
+    }
+
+    pub async fn join(&self, conn: &Uuid, id: &Uuid) -> Result<(), Rejection> {
+        let name = self.name(conn).await?;
+        let mut tournaments = self.tournaments.lock().await;
+        let tournament = tournaments.get_mut(id).ok_or(Rejection::NoSuchTournament)?;
+        if tournament.started {
+            return Err(Rejection::AlreadyStarted);
+        }
+        if !tournament.players.contains(&name) {
+            tournament.players.push(name);
+            self.save(tournament);
+        }
+        Ok(())
+    }
+
+    pub async fn info(&self, id: &Uuid) -> Result<TournamentInfo, Rejection> {
+        self.tournaments
+            .lock()
+            .await
+            .get(id)
+            .map(Tournament::info)
+            .ok_or(Rejection::NoSuchTournament)
+    }
+
+    /// Starts the first round, or recreates the rooms of the current one after a restart.
+    /// Server admins can start any tournament
+    pub async fn start(
+        &self,
+        server: &proto::ServerProtocol,
+        conn: &Uuid,
+        id: &Uuid,
+    ) -> Result<(), Rejection> {
+        let name = self.name(conn).await?;
+        let server_admin = self.players.is_admin(conn).await;
+        {
+            let mut tournaments = self.tournaments.lock().await;
+            let tournament = tournaments.get_mut(id).ok_or(Rejection::NoSuchTournament)?;
+            if tournament.admin != name && !server_admin {
+                return Err(Rejection::NotAdmin);
+            }
+            if tournament.finished {
+                return Err(Rejection::Finished);
+            }
+            self.game(&tournament.game)?;
+            if tournament.players.len() < MATCH_SIZE {
+                return Err(Rejection::NotEnoughPlayers);
+            }
+            tournament.started = true;
+        }
+        self.play(server, id).await;
+        Ok(())
+    }
+
+    /// Counts the result of a match of a tournament, if it was one
+    pub async fn report(&self, server: &proto::ServerProtocol, result: MatchResult) {
+        let id = {
+            let mut tournaments = self.tournaments.lock().await;
+            let found = tournaments.values_mut().find_map(|t| {
+                let m = t
+                    .rounds
+                    .last_mut()?
+                    .iter_mut()
+                    .find(|m| !m.done && m.room == Some(result.room))?;
+                Some((t.id, m))
+            });
+            let (id, m) = match found {
+                Some(found) => found,
+                None => return,
+            };
+            m.winner = result
+                .standings
+                .first()
+                .and_then(|seat| m.players.get(*seat))
+                .copied();
+            m.done = true;
+            id
+        };
+        self.play(server, &id).await;
+    }
+
+    /// The players of a tournament, best rated first
+    async fn seeding(&self, tournament: &Tournament) -> Vec<usize> {
+        let version = match self.game(&tournament.game) {
+            Ok(game) => game.version().clone(),
+            Err(_) => return (0..tournament.players.len()).collect(),
+        };
+        let mut ratings = Vec::with_capacity(tournament.players.len());
+        for (i, name) in tournament.players.iter().enumerate() {
+            let rating = self
+                .stats
+                .player_rating(name, &tournament.game, &version)
+                .await;
+            ratings.push((i, rating));
+        }
+        ratings.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        ratings.into_iter().map(|(i, _)| i).collect()
+    }
+
+    /// Moves on to the next round when the current one is done and creates the rooms of its matches.
+    /// Players that aren't connected forfeit their match.
+    async fn play(&self, server: &proto::ServerProtocol, id: &Uuid) {
+        loop {
+            let mut tournaments = self.tournaments.lock().await;
+            let tournament = match tournaments.get_mut(id) {
+                Some(tournament) if !tournament.finished => tournament,
+                _ => return,
+            };
+            if tournament.round_done() {
+                let seeding = self.seeding(tournament).await;
+                tournament.next_round(&seeding);
+            }
+            if tournament.finished {
+                self.save(tournament);
+                let reply = Reply::Event(Event::TournamentOver {
+                    tournament: *id,
+                    standings: tournament.standings(),
+                });
+                let players = tournament.players.clone();
+                drop(tournaments);
+                for name in players {
+                    if let Some(conn) = self.conn(&name).await {
+                        server.send(&conn, &reply).await.ok();
+                    }
+                }
+                return;
+            }
+            let pending: Vec<usize> = tournament
+                .rounds
+                .last()
+                .unwrap()
+                .iter()
+                .enumerate()
+                .filter(|(_, m)| !m.done && m.room.is_none())
+                .map(|(i, _)| i)
+                .collect();
+            if pending.is_empty() {
+                self.save(tournament);
+                return;
+            }
+            let game = match self.game(&tournament.game) {
+                Ok(game) => game,
+                Err(_) => {
+                    warn!(
+                        "Tournament {} is for {}, which isn't loaded",
+                        id, tournament.game
+                    );
+                    return;
+                }
+            };
+            // The rooms are created with the tournaments locked, so that no match gets two of them
+            // and the result of a room can't come before the room is stored
+            let mut started = Vec::with_capacity(pending.len());
+            for i in pending {
+                let mut conns = Vec::with_capacity(MATCH_SIZE);
+                let mut present = Vec::with_capacity(MATCH_SIZE);
+                for player in &tournament.rounds.last().unwrap()[i].players {
+                    if let Some(conn) = self.conn(&tournament.players[*player]).await {
+                        conns.push(conn);
+                        present.push(*player);
+                    }
+                }
+                let m = &mut tournament.rounds.last_mut().unwrap()[i];
+                if present.len() < m.players.len() {
+                    m.winner = match present[..] {
+                        [winner] => Some(winner),
+                        _ => None,
+                    };
+                    m.done = true;
+                    continue;
+                }
+                match self.rooms.create_match(game.clone(), &conns).await {
+                    Ok((room_id, room)) => {
+                        m.room = Some(room_id);
+                        started.push((room_id, room, conns));
+                    }
+                    Err(e) => {
+                        warn!("Unable to start a match of tournament {}: {:?}", id, e);
+                        m.done = true;
+                    }
+                }
+            }
+            self.save(tournament);
+            drop(tournaments);
+            for (room_id, room, conns) in started {
+                for (seat, conn) in conns.iter().enumerate() {
+                    let reply = Reply::Event(Event::Matched {
+                        room: room_id,
+                        seat,
+                    });
+                    server.send(conn, &reply).await.ok();
+                }
+                changed(server, &room).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tournament(format: TournamentFormat, players: usize) -> Tournament {
+        Tournament {
+            id: Uuid::new_v4(),
+            game: "GAME".to_string(),
+            format,
+            admin: "admin".to_string(),
+            players: (0..players).map(|x| x.to_string()).collect(),
+            rounds: Vec::new(),
+            started: true,
+            finished: false,
+        }
+    }
+
+    /// Finishes the last round, the first player of every match wins it
+    fn play_round(tournament: &mut Tournament) {
+        for m in tournament.rounds.last_mut().unwrap() {
+            m.winner = Some(m.players[0]);
+            m.done = true;
+        }
+    }
+
+    fn pairs(round: &[Match]) -> Vec<Vec<usize>> {
+        round.iter().map(|m| m.players.clone()).collect()
+    }
+
+    #[test]
+    fn swiss_pairs_by_points_without_rematches() {
+        let mut t = tournament(TournamentFormat::Swiss { rounds: 3 }, 4);
+        let seeding = [0, 1, 2, 3];
+        t.next_round(&seeding);
+        assert_eq!(pairs(&t.rounds[0]), [vec![0, 1], vec![2, 3]]);
+        play_round(&mut t);
+        // The winners meet, and so do the losers
+        t.next_round(&seeding);
+        assert_eq!(pairs(&t.rounds[1]), [vec![0, 2], vec![1, 3]]);
+        play_round(&mut t);
+        // 0 would meet 1 by points, but it already met 1 and 2
+        t.next_round(&seeding);
+        assert_eq!(pairs(&t.rounds[2]), [vec![0, 3], vec![1, 2]]);
+        play_round(&mut t);
+        t.next_round(&seeding);
+        assert!(t.finished);
+        assert_eq!(t.points(), [3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn swiss_gives_the_bye_to_the_lowest_player_without_one() {
+        let mut t = tournament(TournamentFormat::Swiss { rounds: 2 }, 3);
+        let seeding = [0, 1, 2];
+        t.next_round(&seeding);
+        assert_eq!(pairs(&t.rounds[0]), [vec![2], vec![0, 1]]);
+        assert!(t.rounds[0][0].done);
+        play_round(&mut t);
+        // 2 is the lowest player again but already had a bye
+        t.next_round(&seeding);
+        assert_eq!(pairs(&t.rounds[1]), [vec![1], vec![0, 2]]);
+    }
+
+    #[test]
+    fn elimination_puts_the_best_seeds_against_the_worst() {
+        let mut t = tournament(TournamentFormat::SingleElimination, 4);
+        let seeding = [3, 1, 0, 2];
+        t.next_round(&seeding);
+        assert_eq!(pairs(&t.rounds[0]), [vec![3, 2], vec![1, 0]]);
+        play_round(&mut t);
+        t.next_round(&seeding);
+        assert_eq!(pairs(&t.rounds[1]), [vec![3, 1]]);
+        play_round(&mut t);
+        t.next_round(&seeding);
+        assert!(t.finished);
+        assert_eq!(t.standings()[0], ("3".to_string(), 2));
+    }
+
+    #[test]
+    fn elimination_gives_the_best_seed_a_bye() {
+        let mut t = tournament(TournamentFormat::SingleElimination, 5);
+        let seeding = [4, 3, 2, 1, 0];
+        t.next_round(&seeding);
+        assert_eq!(pairs(&t.rounds[0]), [vec![4], vec![3, 0], vec![2, 1]]);
+        play_round(&mut t);
+        // A forfeit where nobody won leaves one player less
+        t.rounds[0][2].winner = None;
+        t.next_round(&seeding);
+        assert_eq!(pairs(&t.rounds[1]), [vec![4, 3]]);
+    }
+
+    #[test]
+    fn elimination_needs_two_players() {
+        let mut t = tournament(TournamentFormat::SingleElimination, 1);
+        t.next_round(&[0]);
+        assert!(t.finished);
+        assert!(t.rounds.is_empty());
+    }
+}
@@ -51,3 +51,47 @@ function pop_card_bottom(pile)
     end
     table.remove(pile.cards, 1)
 end
+
+-- whether it is the turn of the player
+function is_turn(player)
+    return game_state().turn == player
+end
+
+-- pass the turn to the next player
+function next_turn()
+    local state = game_state()
+    state.turn = state.turn % #state.players + 1
+end
+
+-- ask the player to pick one of the options, the answer is passed to on_answer(player, option)
+function ask(player, question, options)
+    game_state().prompt = {player = player, question = question, options = options}
+end
+
+-- remember the contents of every table reachable from t, to put them back with restore_state
+function snapshot_state(t, saved)
+    saved = saved or {}
+    if type(t) ~= 'table' or saved[t] ~= nil then
+        return saved
+    end
+    local contents = {}
+    saved[t] = contents
+    for k, v in next, t, nil do
+        contents[k] = v
+        snapshot_state(k, saved)
+        snapshot_state(v, saved)
+    end
+    return saved
+end
+
+-- put back the contents remembered by snapshot_state, keeping the same tables
+function restore_state(saved)
+    for t, contents in next, saved, nil do
+        for k in next, t, nil do
+            t[k] = nil
+        end
+        for k, v in next, contents, nil do
+            t[k] = v
+        end
+    end
+end
@@ -35,16 +35,12 @@ impl Visit for MessageVisitor {
 #[derive(Debug)]
 struct Attributes {
     name: String,
-    target: String,
-    level: Level,
     values: String,
 }
 
 impl From<&tracing::span::Attributes<'_>> for Attributes {
     fn from(attr: &tracing::span::Attributes<'_>) -> Self {
         let name = attr.metadata().name().to_string();
-        let target = attr.metadata().target().to_string();
-        let level = attr.metadata().level().clone();
         let mut values = attr
             .values()
             .to_string()
@@ -53,12 +49,7 @@ impl From<&tracing::span::Attributes<'_>> for Attributes {
         if !values.is_empty() {
             values = format!("({})", values)
         }
-        Self {
-            name,
-            target,
-            level,
-            values,
-        }
+        Self { name, values }
     }
 }
 
@@ -70,12 +61,12 @@ pub enum ApplyTo {
     All,
 }
 
-// #[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum TargetKind {
-    Target(&'static str),
-    Targets(Vec<&'static str>),
-    Span(&'static str),
-    Spans(Vec<&'static str>),
+    Target(String),
+    Targets(Vec<String>),
+    Span(String),
+    Spans(Vec<String>),
 }
 
 pub struct Filter {
@@ -103,14 +94,13 @@ impl Filter {
         let should_show_target = match &self.target {
             None => true,
             Some(t) => match t {
-                TargetKind::Target(t) => t == &metadata.target(),
-                TargetKind::Targets(t) => t.contains(&metadata.target()),
-                TargetKind::Span(s) => spans.contains(s),
-                TargetKind::Spans(s) => s.iter().fold(false, |s, x| s || spans.contains(x))
+                TargetKind::Target(t) => t == metadata.target(),
+                TargetKind::Targets(t) => t.iter().any(|x| x == metadata.target()),
+                TargetKind::Span(s) => spans.contains(&s.as_str()),
+                TargetKind::Spans(s) => s.iter().any(|x| spans.contains(&x.as_str()))
             }
         };
-        println!("spans: {:?}", spans);
-    
+
         if should_show_target {
             Some(should_show_level)
         }else{
@@ -119,6 +109,7 @@ impl Filter {
     }
 }
 
+#[derive(Clone)]
 pub struct Subscriber {
     id: Arc<RwLock<u64>>,
     stack: Arc<RwLock<Vec<Id>>>,
@@ -173,6 +164,22 @@ impl Subscriber {
         self.filters.write().unwrap().push(filter);
     }
 
+    /// Puts the filter before the others, replacing the ones for the same target and output
+    pub fn set_filter(&self, filter: Filter) {
+        let mut filters = self.filters.write().unwrap();
+        filters.retain(|x| x.target != filter.target || x.apply_to != filter.apply_to);
+        filters.insert(0, filter);
+    }
+
+    /// Makes sure everything logged so far is on disk, a clone kept before setting the subscriber can be used
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.main_log.write().unwrap().sync_all()?;
+        for file in self.target_logs.write().unwrap().values_mut() {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
     fn show(&self, metadata: &tracing::Metadata<'_>, apply_to: ApplyTo) -> bool {
         let spans = self.spans.read().unwrap();
         let spans: Vec<&str> = self.stack.read().unwrap().iter().map(|x| spans[x].name.as_str()).collect();
@@ -270,22 +277,22 @@ impl tracing::Subscriber for Subscriber {
 }
 
 fn format_level_colored(level: &Level) -> String {
-    match level {
-        &Level::TRACE => "TRACE".white(),
-        &Level::DEBUG => "DEBUG".light_green(),
-        &Level::INFO => "INFO ".light_cyan(),
-        &Level::WARN => "WARN ".yellow(),
-        &Level::ERROR => "ERROR".red(),
+    match *level {
+        Level::TRACE => "TRACE".white(),
+        Level::DEBUG => "DEBUG".light_green(),
+        Level::INFO => "INFO ".light_cyan(),
+        Level::WARN => "WARN ".yellow(),
+        Level::ERROR => "ERROR".red(),
     }
     .to_string()
 }
 
 fn format_level(level: &Level) -> &'static str {
-    match level {
-        &Level::TRACE => "TRACE",
-        &Level::DEBUG => "DEBUG",
-        &Level::INFO => "INFO ",
-        &Level::WARN => "WARN ",
-        &Level::ERROR => "ERROR",
+    match *level {
+        Level::TRACE => "TRACE",
+        Level::DEBUG => "DEBUG",
+        Level::INFO => "INFO ",
+        Level::WARN => "WARN ",
+        Level::ERROR => "ERROR",
     }
 }
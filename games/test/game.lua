@@ -3,6 +3,7 @@
 name = "TEST"
 version = "1.0.0"
 players = {2, 10}
+-- spectators = false -- forbids spectating rooms of this game
 
 function setup(players)
 	local deck = deepcopy(Pile)
@@ -0,0 +1,9 @@
+-- Plays a seat using only what that seat can see
+
+function choose_action(view)
+	return view.legal_actions[1]
+end
+
+function answer_prompt(view, prompt)
+	return 1
+end
@@ -3,17 +3,33 @@
 name = "UNO"
 version = "1.0.0"
 players = {2, 10}
+-- spectators = false -- forbids spectating rooms of this game
+turn_time = 30 -- seconds, when they run out the player draws and passes
+draw_pile = 1
 
-function setup(players)
+-- chosen per room, handlers can read them from game_state().options
+options = {
+	decks = {kind = "range", min = 1, max = 4, default = 1, description = "Copies of the deck to play with"},
+}
+
+function setup(players, options)
 	local deck = deepcopy(Pile)
-	deck.cards = shuffle({
-		PlusFour, PlusFour, PlusFour, PlusFour,
-		BlueZero
-	})
+	local cards = {}
+	for _ = 1, options.decks do
+		for _, card in ipairs({PlusFour, PlusFour, PlusFour, PlusFour, BlueZero}) do
+			table.insert(cards, card)
+		end
+	end
+	deck.cards = shuffle(cards)
 	deck.face_down = true
 
-	function deck:on_click(player_piles)
+	function deck:on_click(player_piles, player)
+		if not is_turn(player) then
+			error('Not your turn')
+		end
 		add_card(player_piles[1], pop_card(self))
+		irreversible() -- the player has seen the card
+		next_turn()
 		return player_piles
 	end
 
@@ -23,6 +39,23 @@ function setup(players)
 	return {deck, stack},{hand} -- piles / player piles
 end
 
+-- only drawing is possible for now, and only on your turn
+function legal_actions(player, state)
+	if state.turn ~= player then
+		return {}
+	end
+	return {{common = 1}}
+end
+
+-- the game ends when the deck runs out, the player with the fewest cards wins
+function check_winner(state)
+	return #state.piles[1].cards == 0
+end
+
+function score(player, state)
+	return -#state.players[player][1].cards
+end
+
 Pile = {face_down = false, cards = {}}
 
 PlusFour = {image = "cards/plus4.png", kind = "+4", color = "any"}